@@ -3,6 +3,8 @@ use anyhow::Result;
 use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
 use num_traits::Num;
 use std::ops::MulAssign;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 pub trait Sample: Sized + Num + Copy + MulAssign + Send + 'static {
@@ -15,16 +17,16 @@ pub trait Sample: Sized + Num + Copy + MulAssign + Send + 'static {
 
 impl Sample for f32 {
     fn from_i8(n: i8) -> Self {
-        n as f32 / i8::max_value() as f32
+        n as f32 / i8::MAX as f32
     }
     fn from_i16(n: i16) -> Self {
-        n as f32 / i16::max_value() as f32
+        n as f32 / i16::MAX as f32
     }
     fn from_i24(n: i32) -> Self {
         n as f32 / 8388608.0
     }
     fn from_i32(n: i32) -> Self {
-        n as f32 / i32::max_value() as f32
+        n as f32 / i32::MAX as f32
     }
 }
 
@@ -64,8 +66,8 @@ impl Audio {
 
     pub fn amplify_in_place(&mut self, factor: f32) {
         for channel in self.data.iter_mut() {
-            for i in 0..channel.len() {
-                channel[i] *= factor;
+            for sample in channel.iter_mut() {
+                *sample *= factor;
             }
         }
     }
@@ -74,6 +76,18 @@ impl Audio {
         self.data.rotate_right(1);
     }
 
+    /// Convert stereo left/right channels into mid/side channels
+    pub fn encode_mid_side_in_place(&mut self) {
+        assert!(self.data.len() == 2);
+        let (left, right) = self.data.split_at_mut(1);
+        for (l, r) in left[0].iter_mut().zip(right[0].iter_mut()) {
+            let mid = (*l + *r) / 2.0;
+            let side = (*l - *r) / 2.0;
+            *l = mid;
+            *r = side;
+        }
+    }
+
     pub fn fade_in(&mut self, start: Duration, dur: Duration) {
         self.fade_in_at_sample(self.duration_to_sample(start), self.duration_to_sample(dur))
     }
@@ -84,8 +98,8 @@ impl Audio {
             return;
         }
         for channel in self.data.iter_mut() {
-            for i in 0..start {
-                channel[i] = 0.0;
+            for sample in channel[..start].iter_mut() {
+                *sample = 0.0;
             }
             for p in 0..dur {
                 channel[start + p] *= math::sqrt_interp(0.0, 1.0, p as f32 / dur as f32)
@@ -103,8 +117,8 @@ impl Audio {
             return;
         }
         for channel in self.data.iter_mut() {
-            for i in start + dur..channel.len() {
-                channel[i] = 0.0;
+            for sample in channel[start + dur..].iter_mut() {
+                *sample = 0.0;
             }
             for p in 0..dur {
                 channel[start + p] *= math::sqrt_interp(1.0, 0.0, p as f32 / dur as f32)
@@ -125,7 +139,7 @@ impl Audio {
                 let dur_in_samples = (dur.as_secs_f64() * self.spec.sample_rate as f64) as usize;
                 start_sample_pos + dur_in_samples
             }
-            None => self.data.get(0).unwrap().len(),
+            None => self.data.first().unwrap().len(),
         }
     }
 
@@ -138,11 +152,47 @@ impl Audio {
     }
 }
 
+/// Convert mid/side samples back into left/right in place, leaving the
+/// left channel in `mid` and the right in `side`
+pub fn decode_mid_side(mid: &mut [f32], side: &mut [f32]) {
+    for (m, s) in mid.iter_mut().zip(side.iter_mut()) {
+        let left = *m + *s;
+        let right = *m - *s;
+        *m = left;
+        *s = right;
+    }
+}
+
+/// How many samples a bus is expected to carry in total, shared between its
+/// producer and consumer so a live freeze can move the end while it plays
+#[derive(Debug, Clone)]
+pub struct ExpectedLength(Arc<AtomicUsize>);
+
+impl ExpectedLength {
+    const UNKNOWN: usize = usize::MAX;
+
+    pub fn new(samples: Option<usize>) -> Self {
+        ExpectedLength(Arc::new(AtomicUsize::new(samples.unwrap_or(Self::UNKNOWN))))
+    }
+
+    pub fn get(&self) -> Option<usize> {
+        match self.0.load(Ordering::Relaxed) {
+            Self::UNKNOWN => None,
+            samples => Some(samples),
+        }
+    }
+
+    pub fn set(&self, samples: Option<usize>) {
+        self.0
+            .store(samples.unwrap_or(Self::UNKNOWN), Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug)]
 pub struct AudioBus {
     pub spec: AudioSpec,
     pub channels: Vec<Receiver<Vec<f32>>>,
-    pub expected_total_samples: Option<usize>,
+    pub expected_total_samples: ExpectedLength,
 }
 
 const INTO_AUDIO_DRAIN_TIMEOUT: Duration = Duration::from_millis(5);
@@ -173,7 +223,7 @@ impl AudioBus {
 
     pub fn from_audio(audio: Audio) -> Self {
         let spec = audio.spec;
-        let expected_total_samples = Some(audio.data[0].len());
+        let expected_total_samples = ExpectedLength::new(Some(audio.data[0].len()));
         let channels: Vec<Receiver<Vec<f32>>> = audio
             .data
             .into_iter()
@@ -204,7 +254,7 @@ impl AudioBus {
         (
             AudioBus {
                 spec,
-                expected_total_samples,
+                expected_total_samples: ExpectedLength::new(expected_total_samples),
                 channels: receivers,
             },
             senders,
@@ -224,6 +274,7 @@ impl AudioBus {
 }
 
 #[cfg(test)]
+#[allow(clippy::get_first)]
 mod test {
     use super::*;
     use crate::test_utils::*;
@@ -286,6 +337,29 @@ mod test {
         assert_almost_eq_by_element(audio.data[1].clone(), vec![6.0, 5.0]);
     }
 
+    #[test]
+    fn test_encode_mid_side_in_place() {
+        let mut audio = generate_audio(0.0, 2, 2, 44100);
+        audio.data[0] = vec![1.0, 0.5];
+        audio.data[1] = vec![1.0, -0.5];
+        audio.encode_mid_side_in_place();
+        assert_almost_eq_by_element(audio.data[0].clone(), vec![1.0, 0.0]);
+        assert_almost_eq_by_element(audio.data[1].clone(), vec![0.0, 0.5]);
+    }
+
+    #[test]
+    fn test_decode_mid_side_reverses_encoding() {
+        let mut audio = generate_audio(0.0, 3, 2, 44100);
+        audio.data[0] = vec![0.3, -0.2, 0.9];
+        audio.data[1] = vec![0.1, 0.4, -0.7];
+        let original = audio.data.clone();
+        audio.encode_mid_side_in_place();
+        let (mid, side) = audio.data.split_at_mut(1);
+        decode_mid_side(&mut mid[0], &mut side[0]);
+        assert_almost_eq_by_element(audio.data[0].clone(), original[0].clone());
+        assert_almost_eq_by_element(audio.data[1].clone(), original[1].clone());
+    }
+
     #[test]
     fn test_fade_in_at_sample() {
         let mut audio = generate_audio(1.0, 10, 2, 44100);
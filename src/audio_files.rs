@@ -68,7 +68,7 @@ where
     fn write_into_channels(&mut self, channels: Vec<Vec<f32>>) -> Result<()> {
         // Sanity check that each channel has the same length, and that there is at least one channel
         debug_assert!(HashSet::<usize>::from_iter(channels.iter().map(|c| c.len())).len() == 1);
-        let samples_per_channel = channels.get(0).unwrap().len();
+        let samples_per_channel = channels.first().unwrap().len();
 
         for i in 0..samples_per_channel {
             for channel in &channels {
@@ -100,7 +100,7 @@ impl WavReader<io::BufReader<fs::File>> {
 
 impl<R> WavReader<R> {
     fn validate_num_samples(num_samples: u32, channels: u16) -> Result<()> {
-        return if num_samples % channels as u32 != 0 {
+        if !num_samples.is_multiple_of(channels as u32) {
             Err(anyhow!(
                 "num_samples {} is not a multiple of channel count {}",
                 num_samples,
@@ -108,7 +108,7 @@ impl<R> WavReader<R> {
             ))
         } else {
             Ok(())
-        };
+        }
     }
 }
 
@@ -305,7 +305,7 @@ where
             let next_frame = self.underlier.next_frame().ok()?;
             debug_assert!(next_frame.channels as u16 == self.spec.channels);
             debug_assert!(next_frame.sample_rate as u32 == self.spec.sample_rate);
-            debug_assert!(next_frame.data.len() > 0);
+            debug_assert!(!next_frame.data.is_empty());
             self.buffer_i = 1;
             self.buffer = next_frame.data;
             Some(unsafe { *self.buffer.get_unchecked(0) })
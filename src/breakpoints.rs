@@ -0,0 +1,181 @@
+use crate::duration_parser;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// A value that varies over time, defined by `(seconds, value)` breakpoints
+///
+/// The value is interpolated linearly between breakpoints and held constant
+/// before the first and after the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoints {
+    points: Vec<(f32, f32)>,
+}
+
+impl Breakpoints {
+    pub fn constant(value: f32) -> Breakpoints {
+        Breakpoints {
+            points: vec![(0.0, value)],
+        }
+    }
+
+    /// Read breakpoints from the file at `arg` if there is one, or else
+    /// parse `arg` itself with breakpoints separated by semicolons.
+    pub fn from_arg<F>(arg: &str, parse_value: F) -> Result<Breakpoints>
+    where
+        F: Fn(&str) -> Result<f32>,
+    {
+        let path = Path::new(arg);
+        if path.is_file() {
+            let src = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            Breakpoints::parse(&src, parse_value)
+                .with_context(|| format!("Invalid breakpoints in {}", path.display()))
+        } else {
+            Breakpoints::parse(&arg.replace(';', "\n"), parse_value)
+        }
+    }
+
+    /// Parse one `time, value` breakpoint per line.
+    ///
+    /// Times use the same `hh:mm:ss.ss` format as `--start` and must
+    /// increase from line to line. Blank lines and lines starting with `#`
+    /// are ignored.
+    pub fn parse<F>(src: &str, parse_value: F) -> Result<Breakpoints>
+    where
+        F: Fn(&str) -> Result<f32>,
+    {
+        let mut points: Vec<(f32, f32)> = vec![];
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (time_str, value_str) = match line.split_once(',') {
+                Some(fields) => fields,
+                None => bail!("Line {}: expected 'time, value'", i + 1),
+            };
+            let time = duration_parser::parse_duration(time_str.trim())
+                .with_context(|| format!("Line {}: invalid time", i + 1))?
+                .as_secs_f32();
+            let value = parse_value(value_str.trim()).with_context(|| format!("Line {}", i + 1))?;
+            if let Some((last_time, _)) = points.last() {
+                if time <= *last_time {
+                    bail!("Line {}: times must increase from line to line", i + 1);
+                }
+            }
+            points.push((time, value));
+        }
+        if points.is_empty() {
+            bail!("No breakpoints given");
+        }
+        Ok(Breakpoints { points })
+    }
+
+    /// The value, if it's the same all the time
+    pub fn constant_value(&self) -> Option<f32> {
+        match self.points[..] {
+            [(_, value)] => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value at `time` seconds
+    pub fn value_at(&self, time: f32) -> f32 {
+        self.value_at_f64(time as f64) as f32
+    }
+
+    fn value_at_f64(&self, time: f64) -> f64 {
+        let next = self.points.partition_point(|(t, _)| *t as f64 <= time);
+        if next == 0 {
+            return self.points[0].1 as f64;
+        }
+        if next == self.points.len() {
+            return self.points[next - 1].1 as f64;
+        }
+        let (t0, v0) = self.points[next - 1];
+        let (t1, v1) = self.points[next];
+        let (t0, v0, t1, v1) = (t0 as f64, v0 as f64, t1 as f64, v1 as f64);
+        v0 + (v1 - v0) * (time - t0) / (t1 - t0)
+    }
+
+    /// The integral of the value from 0 to `time` seconds, in double
+    /// precision since it's used to count samples over hours of audio
+    pub fn integral(&self, time: f64) -> f64 {
+        // the value is linear between breakpoints, so the trapezoid rule is exact
+        let mut times = vec![0.0];
+        times.extend(
+            self.points
+                .iter()
+                .map(|(t, _)| *t as f64)
+                .filter(|t| *t > 0.0 && *t < time),
+        );
+        times.push(time);
+        times
+            .windows(2)
+            .map(|span| {
+                (span[1] - span[0]) * (self.value_at_f64(span[0]) + self.value_at_f64(span[1]))
+                    / 2.0
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use test_case::test_case;
+
+    const RAMP: &str = "
+        # start at 2, ramp to 50 through the middle and relax back out
+        0, 2
+        10, 50
+        0:20, 50
+        30.5, 2
+    ";
+
+    fn parse_ramp() -> Breakpoints {
+        Breakpoints::parse(RAMP, |s| Ok(s.parse()?)).unwrap()
+    }
+
+    #[test]
+    fn parse_reads_breakpoints() {
+        assert_eq!(
+            parse_ramp().points,
+            vec![(0.0, 2.0), (10.0, 50.0), (20.0, 50.0), (30.5, 2.0)]
+        );
+    }
+
+    #[test]
+    fn from_arg_parses_inline_breakpoints() {
+        let breakpoints = Breakpoints::from_arg("0, 2; 10, 50", |s| Ok(s.parse()?)).unwrap();
+        assert_eq!(breakpoints.points, vec![(0.0, 2.0), (10.0, 50.0)]);
+    }
+
+    #[test_case("" ; "empty")]
+    #[test_case("0 2" ; "missing comma")]
+    #[test_case("0, 2\n0, 3" ; "repeated time")]
+    #[test_case("5, 2\n1, 3" ; "decreasing time")]
+    #[test_case("later, 2" ; "nonsense time")]
+    #[test_case("0, fast" ; "nonsense value")]
+    fn parse_rejects_invalid_breakpoints(src: &str) {
+        assert!(Breakpoints::parse(src, |s| Ok(s.parse()?)).is_err());
+    }
+
+    #[test_case(-1.0, 2.0 ; "before first breakpoint")]
+    #[test_case(0.0, 2.0 ; "at first breakpoint")]
+    #[test_case(5.0, 26.0 ; "between breakpoints")]
+    #[test_case(15.0, 50.0 ; "flat segment")]
+    #[test_case(40.0, 2.0 ; "after last breakpoint")]
+    fn value_at_interpolates(time: f32, expected: f32) {
+        assert_almost_eq(parse_ramp().value_at(time), expected);
+    }
+
+    #[test_case(10.0, 260.0 ; "within ramp")]
+    #[test_case(20.0, 760.0 ; "through flat segment")]
+    #[test_case(40.0, 1052.0 ; "past last breakpoint")]
+    fn integral_sums_segments(time: f64, expected: f32) {
+        assert_almost_eq(parse_ramp().integral(time) as f32, expected);
+    }
+}
@@ -1,10 +1,82 @@
-use std::f32;
-
-/// Taken from paulstretch
-pub fn hanning_crossfade_compensation(len: usize) -> Vec<f32> {
-    let two_pi = f32::consts::PI * 2.0;
-    let hinv_sqrt2 = (1.0 + f32::sqrt(0.5).sqrt()) * 0.5;
-    (0..len)
-        .map(|i| 0.5 - ((1.0 - hinv_sqrt2) * f32::cos((i as f32 * two_pi) / (len - 1) as f32)))
+/// Compensation for overlap-adding frames with random phases shaped by
+/// `window` every `hop` samples.
+///
+/// Random phases make consecutive frames uncorrelated, so their powers add:
+/// a frame carries the mean squared window's share of its input's power,
+/// spread out under the resynthesis window. This is the inverse of the
+/// resulting amplitude over each hop, making the overall gain 1.
+pub fn random_phase_overlap_compensation(window: &[f32], hop: usize) -> Vec<f32> {
+    let mean_square = window.iter().map(|w| w * w).sum::<f32>() / window.len() as f32;
+    overlapped_squared_window(window, hop)
+        .iter()
+        .map(|summed_power| 1.0 / (mean_square * summed_power).sqrt())
         .collect()
 }
+
+/// Compensation for overlap-adding frames shaped by `window` twice (once on
+/// analysis and once on resynthesis) every `hop` samples.
+///
+/// Phase-coherent frames sum exactly, so this is the inverse of the summed
+/// squared window over each hop.
+pub fn squared_window_overlap_compensation(window: &[f32], hop: usize) -> Vec<f32> {
+    overlapped_squared_window(window, hop)
+        .iter()
+        .map(|summed| 1.0 / summed)
+        .collect()
+}
+
+/// The squared window summed over every frame overlapping each sample of a hop
+fn overlapped_squared_window(window: &[f32], hop: usize) -> Vec<f32> {
+    (0..hop)
+        .map(|i| window.iter().skip(i).step_by(hop).map(|w| w * w).sum())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::windows;
+
+    #[test]
+    fn squared_window_overlap_compensation_flattens_hanning() {
+        let window = windows::hanning(64);
+        let compensation = squared_window_overlap_compensation(&window, 32);
+        let flattened: Vec<f32> = (0..32)
+            .map(|i| (window[i].powi(2) + window[i + 32].powi(2)) * compensation[i])
+            .collect();
+        assert_almost_eq_by_element(flattened, vec![1.0; 32]);
+    }
+
+    #[test]
+    fn random_phase_overlap_compensation_rectangular() {
+        let compensation = random_phase_overlap_compensation(&windows::rectangular(4), 2);
+        assert_almost_eq_by_element(compensation, vec![0.70710677, 0.70710677]);
+    }
+
+    #[test]
+    fn random_phase_overlap_compensation_flattens_blackman_harris() {
+        let window = windows::blackman_harris(64);
+        let compensation = random_phase_overlap_compensation(&window, 32);
+        let flattened: Vec<f32> = (0..32)
+            .map(|i| (window[i].powi(2) + window[i + 32].powi(2)).sqrt() * compensation[i])
+            .collect();
+        // what's left is the inverse of the window's RMS level
+        let rms = (window.iter().map(|w| w * w).sum::<f32>() / 64.0).sqrt();
+        assert_almost_eq_by_element(flattened, vec![1.0 / rms; 32]);
+    }
+
+    #[test]
+    fn squared_window_overlap_compensation_rectangular() {
+        let compensation = squared_window_overlap_compensation(&windows::rectangular(4), 2);
+        assert_almost_eq_by_element(compensation, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn squared_window_overlap_compensation_at_quarter_window_hops() {
+        // four squared Hann windows a quarter apart sum to a constant 1.5
+        let compensation =
+            squared_window_overlap_compensation(&windows::hanning(1025)[..1024], 256);
+        assert_almost_eq_by_element(compensation, vec![1.0 / 1.5; 256]);
+    }
+}
@@ -4,10 +4,10 @@ use anyhow::{bail, Result};
 
 pub fn parse_duration(duration_str: &str) -> Result<Duration> {
     let parts: Vec<&str> = duration_str.rsplit(":").collect();
-    if parts.len() > 3 || parts.len() < 1 {
+    if parts.len() > 3 || parts.is_empty() {
         bail!("Invalid duration specification".to_string());
     }
-    let seconds_str = parts.get(0).unwrap();
+    let seconds_str = parts.first().unwrap();
     let maybe_minutes_str = parts.get(1);
     let maybe_hours_str = parts.get(2);
 
@@ -1,109 +1,348 @@
-use crate::hotswapper;
-use crossbeam_channel::Receiver;
-use libloading::{Library, Symbol};
-use rand::Rng;
+use crate::formants::FormantPreserver;
+use crate::kernel::FrequencyKernel;
+use crate::phase_vocoder::{PhaseLocking, PhaseVocoder};
+use crate::transients::TransientDetector;
+use anyhow::{bail, Error, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
 use rustfft::num_complex::Complex32;
-use rustfft::{Fft, FftPlanner};
 use std::f32;
-use std::panic;
-use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
-const TWO_PI: f32 = f32::consts::PI;
+const TWO_PI: f32 = f32::consts::PI * 2.0;
+
+/// How output phases are chosen when resynthesizing analyzed windows
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResynthMode {
+    /// Paulstretch-style resynthesis which replaces every phase with a random
+    /// one. Smears everything, which is great for ambient drones.
+    RandomPhase,
+    /// Phase-coherent resynthesis which propagates each bin's phase at its
+    /// estimated instantaneous frequency. Keeps speech and percussion
+    /// intelligible at small stretch factors.
+    PhaseVocoder(PhaseLocking),
+}
+
+impl FromStr for ResynthMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random-phase" | "paulstretch" => Ok(ResynthMode::RandomPhase),
+            "phase-vocoder" | "vocoder" => Ok(ResynthMode::PhaseVocoder(PhaseLocking::default())),
+            _ => bail!(
+                "Invalid resynthesis mode '{}'; expected 'random-phase' or 'phase-vocoder'",
+                s
+            ),
+        }
+    }
+}
+
+/// Where the milliseconds passed to frequency kernels come from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum KernelClock {
+    /// Milliseconds since the Unix epoch, so kernels follow real time while playing live
+    #[default]
+    WallClock,
+    /// Milliseconds of output audio before each window, so renders are reproducible
+    AudioTime,
+}
+
+/// How random phases relate across the channels of multichannel audio
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ChannelPhases {
+    /// Every channel's phases are rotated by the same random offsets, which
+    /// keeps the phase differences between channels and so the stereo image.
+    #[default]
+    Linked,
+    /// Every channel gets its own unrelated random phases, which decorrelates
+    /// the channels into a wide, diffuse image that combs when folded to mono.
+    Wide,
+}
+
+impl FromStr for ChannelPhases {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "linked" => Ok(ChannelPhases::Linked),
+            "wide" => Ok(ChannelPhases::Wide),
+            _ => bail!(
+                "Invalid channel phases '{}'; expected 'linked' or 'wide'",
+                s
+            ),
+        }
+    }
+}
+
+/// Source of the random phases used by random-phase resynthesis
+///
+/// Each window's phases are drawn from a generator seeded by the window's
+/// index, so rendering can start from any window and still draw the same
+/// phases as rendering everything before it.
+#[derive(Debug, Clone)]
+pub struct PhaseRandomizer {
+    seed: u64,
+    /// Mixed into the generator's seed alongside `seed`, so each wide
+    /// channel gets its own phases. It's 0 for every linked channel.
+    channel: u64,
+    linked: bool,
+    /// Index of the next window to randomize
+    window: u64,
+}
+
+impl PhaseRandomizer {
+    /// One randomizer for each of `channels` channels.
+    ///
+    /// Linked randomizers share a seed, so as long as each one is used for
+    /// the same number of windows they produce identical phase offsets.
+    pub fn for_channels(
+        channels: usize,
+        channel_phases: ChannelPhases,
+        seed: u64,
+    ) -> Vec<PhaseRandomizer> {
+        (0..channels)
+            .map(|channel| match channel_phases {
+                ChannelPhases::Linked => PhaseRandomizer {
+                    seed,
+                    channel: 0,
+                    linked: true,
+                    window: 0,
+                },
+                ChannelPhases::Wide => PhaseRandomizer {
+                    seed,
+                    channel: channel as u64,
+                    linked: false,
+                    window: 0,
+                },
+            })
+            .collect()
+    }
+
+    /// Randomize the phases of the non-negative frequency half of a spectrum in place.
+    ///
+    /// The next window's phases are drawn even when `keep_phases` is set,
+    /// so linked channels stay in step whatever each one decides.
+    fn randomize(&mut self, spectrum: &mut [Complex32], keep_phases: bool) {
+        let mut rng = self.window_rng();
+        self.window += 1;
+        for bin in spectrum.iter_mut() {
+            let phase = rng.gen_range(0.0..TWO_PI);
+            if keep_phases {
+                continue;
+            }
+            *bin = if self.linked {
+                *bin * Complex32::from_polar(1.0, phase)
+            } else {
+                Complex32::from_polar(bin.norm(), phase)
+            };
+        }
+    }
+
+    /// The generator for the next window's phases
+    fn window_rng(&self) -> StdRng {
+        let mut seed = [0; 32];
+        seed[..8].copy_from_slice(&self.seed.to_le_bytes());
+        seed[8..16].copy_from_slice(&self.window.to_le_bytes());
+        seed[16..24].copy_from_slice(&self.channel.to_le_bytes());
+        StdRng::from_seed(seed)
+    }
+}
 
 pub struct ReFFT {
-    forward_fft: Arc<dyn Fft<f32>>,
-    inverse_fft: Arc<dyn Fft<f32>>,
+    forward_fft: Arc<dyn RealToComplex<f32>>,
+    inverse_fft: Arc<dyn ComplexToReal<f32>>,
     window_len: usize,
     window: Vec<f32>,
-    kernel_recv: Option<Receiver<Library>>,
-    kernels: Vec<Library>,
+    /// Windowed samples on their way into the forward FFT, then resynthesized
+    /// samples on their way out of the inverse FFT
+    samples_buf: Vec<f32>,
+    /// Non-negative frequency half of the current window's spectrum
+    spectrum: Vec<Complex32>,
+    scratch: Vec<Complex32>,
+    phase_vocoder: Option<PhaseVocoder>,
+    transient_detector: Option<TransientDetector>,
+    phase_randomizer: PhaseRandomizer,
+    formant_preserver: Option<FormantPreserver>,
+    /// Spectrum resynthesized in place of the input while frozen
+    frozen_spectrum: Option<Vec<Complex32>>,
+    /// Stages of frequency kernels, run in order
+    kernels: Vec<FrequencyKernel>,
 }
 
 impl ReFFT {
-    pub fn new(window: Vec<f32>, kernel_src: Option<PathBuf>) -> ReFFT {
+    /// When `transient_sensitivity` is given, windows detected as the onset
+    /// of a transient are resynthesized with their original phases so
+    /// attacks stay sharp. When `formant_preserver` is given, spectral
+    /// envelopes are corrected to stay in place through pitch shifts. When
+    /// `kernels` are given, every spectrum is run through each in turn before
+    /// resynthesis.
+    pub fn new(
+        window: Vec<f32>,
+        mode: ResynthMode,
+        transient_sensitivity: Option<f32>,
+        phase_randomizer: PhaseRandomizer,
+        formant_preserver: Option<FormantPreserver>,
+        kernels: Vec<FrequencyKernel>,
+    ) -> ReFFT {
         let window_len = window.len();
-        let mut planner = FftPlanner::new();
+        let mut planner = RealFftPlanner::new();
         let forward_fft = planner.plan_fft_forward(window_len);
         let inverse_fft = planner.plan_fft_inverse(window_len);
-        // TODO maybe need to block on the initial compilation?
-        let kernel_recv = kernel_src.map(|src| hotswapper::hotswap(src).unwrap());
+        let scratch_len = forward_fft
+            .get_scratch_len()
+            .max(inverse_fft.get_scratch_len());
+        let phase_vocoder = match mode {
+            ResynthMode::RandomPhase => None,
+            ResynthMode::PhaseVocoder(locking) => Some(PhaseVocoder::new(window_len, locking)),
+        };
+        let transient_detector = transient_sensitivity
+            .map(|sensitivity| TransientDetector::new(window_len, sensitivity));
         ReFFT {
+            samples_buf: forward_fft.make_input_vec(),
+            spectrum: forward_fft.make_output_vec(),
+            scratch: vec![Complex32::new(0.0, 0.0); scratch_len],
             forward_fft,
             inverse_fft,
             window_len,
             window,
-            kernel_recv,
-            kernels: vec![],
+            phase_vocoder,
+            transient_detector,
+            phase_randomizer,
+            formant_preserver,
+            frozen_spectrum: None,
+            kernels,
         }
     }
 
-    pub fn resynth(&mut self, samples: &[f32]) -> Vec<f32> {
-        let mut fft_result = self.forward_fft(samples);
-        if self.kernel_recv.is_some() {
-            fft_result = self.apply_kernel_to_fft_result(fft_result);
+    /// Hold the spectrum of `samples`, resynthesizing it with fresh random
+    /// phases in place of whatever is passed to `resynth` until released.
+    pub fn freeze(&mut self, samples: &[f32]) {
+        self.forward_fft(samples);
+        self.frozen_spectrum = Some(self.spectrum.clone());
+    }
+
+    pub fn release(&mut self) {
+        self.frozen_spectrum = None;
+        // phases held from before the freeze no longer follow on from the input
+        if let Some(phase_vocoder) = &mut self.phase_vocoder {
+            phase_vocoder.reset();
         }
-        self.resynth_from_fft_result(fft_result)
     }
 
-    fn forward_fft(&self, samples: &[f32]) -> Vec<Complex32> {
-        let mut buf: Vec<Complex32> = samples
-            .iter()
-            .zip(&self.window)
-            .map(|(s, w)| Complex32::new(s * w, 0.0))
-            .collect();
-        if buf.len() < self.window_len {
-            buf.extend(vec![Complex32::new(0.0, 0.0); self.window_len - buf.len()]);
+    pub fn is_frozen(&self) -> bool {
+        self.frozen_spectrum.is_some()
+    }
+
+    /// How many previous windows resynthesizing a window depends on, besides
+    /// the phase vocoder's phases, which follow on from every window before
+    pub fn memory_frames(&self) -> usize {
+        match self.transient_detector {
+            Some(_) => TransientDetector::MEMORY_WINDOWS,
+            None => 0,
         }
-        self.forward_fft.process(&mut buf);
-        buf
     }
 
-    fn resynth_from_fft_result(&self, fft_result: Vec<Complex32>) -> Vec<f32> {
-        let mut rng = rand::thread_rng();
-        let mut buf: Vec<Complex32> = fft_result
-            .iter()
-            .map(|c| Complex32::new(0.0, rng.gen_range(0.0..TWO_PI)).exp() * c.norm())
-            .collect();
-        self.inverse_fft.process(&mut buf);
-        buf.iter()
-            .zip(&self.window)
-            .map(|(c, w)| (c.re / self.window_len as f32) * w)
-            .collect()
+    /// Bypass the kernel at `stage` in the chain, or bring it back. Stages
+    /// past the end of the chain are ignored.
+    pub fn bypass_kernel(&mut self, stage: usize, bypassed: bool) {
+        if let Some(kernel) = self.kernels.get_mut(stage) {
+            kernel.set_bypassed(bypassed);
+        }
     }
 
-    fn apply_kernel_to_fft_result(&mut self, fft_result: Vec<Complex32>) -> Vec<Complex32> {
-        // use catch_unwind to make sure we dont use the new lib if its call panics
-        if let Ok(lib) = self.kernel_recv.as_ref().unwrap().try_recv() {
-            info!("Got new kernel");
-            self.kernels.push(lib);
+    /// Draw random phases for the `frame`th window next, as if every window
+    /// before it had been randomized
+    pub fn seek(&mut self, frame: usize) {
+        self.phase_randomizer.window = frame as u64;
+        for kernel in &mut self.kernels {
+            kernel.seek(frame);
         }
-        let maybe_lib = self.kernels.last();
-        if maybe_lib.is_none() {
-            return fft_result;
+    }
+
+    /// Analyze and resynthesize one window of samples.
+    ///
+    /// `analysis_hop` and `synthesis_hop` are the distances in samples from the
+    /// previous window in the input and output respectively. They are only
+    /// used by phase-coherent resynthesis. `pitch` is the ratio the output
+    /// will be resampled by, and is only used for formant preservation.
+    /// `audio_time` is how much output precedes the window, which kernels
+    /// are given when using the audio time clock.
+    ///
+    /// The resynthesized window is only valid until the next call.
+    pub fn resynth(
+        &mut self,
+        samples: &[f32],
+        analysis_hop: usize,
+        synthesis_hop: usize,
+        pitch: f32,
+        audio_time: Duration,
+    ) -> &[f32] {
+        let is_transient = match &self.frozen_spectrum {
+            Some(frozen_spectrum) => {
+                self.spectrum.copy_from_slice(frozen_spectrum);
+                false
+            }
+            None => {
+                self.forward_fft(samples);
+                match &mut self.transient_detector {
+                    Some(detector) => detector.detect(&self.spectrum),
+                    None => false,
+                }
+            }
+        };
+        for kernel in &mut self.kernels {
+            kernel.apply(&mut self.spectrum, audio_time);
+        }
+        if let Some(formant_preserver) = &mut self.formant_preserver {
+            formant_preserver.correct(&mut self.spectrum, pitch);
         }
-        let kernel_input = fft_result.iter().map(|c| (c.re, c.im)).collect();
-        let lib = maybe_lib.unwrap();
-        match panic::catch_unwind(move || {
-            let time_ms = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as usize;
-            let symbol: Symbol<fn(usize, Vec<(f32, f32)>) -> Vec<(f32, f32)>> =
-                unsafe { lib.get(b"apply\0").unwrap() };
-            let kernel_output = symbol(time_ms, kernel_input);
-            kernel_output
-                .iter()
-                .map(|c| Complex32 { re: c.0, im: c.1 })
-                .collect()
-        }) {
-            Ok(applied) => applied,
-            Err(_) => {
-                warn!("kernel panicked, retrying with last or noop.");
-                self.kernels.pop();
-                self.apply_kernel_to_fft_result(fft_result)
+        self.resynth_from_spectrum(analysis_hop, synthesis_hop, is_transient);
+        &self.samples_buf
+    }
+
+    /// Window `samples` and analyze them into `self.spectrum`
+    fn forward_fft(&mut self, samples: &[f32]) {
+        for (i, sample) in self.samples_buf.iter_mut().enumerate() {
+            *sample = samples.get(i).map_or(0.0, |s| s * self.window[i]);
+        }
+        self.forward_fft
+            .process_with_scratch(&mut self.samples_buf, &mut self.spectrum, &mut self.scratch)
+            .unwrap();
+    }
+
+    /// Choose output phases for `self.spectrum` and resynthesize it into `self.samples_buf`
+    fn resynth_from_spectrum(
+        &mut self,
+        analysis_hop: usize,
+        synthesis_hop: usize,
+        is_transient: bool,
+    ) {
+        let frozen = self.is_frozen();
+        match &mut self.phase_vocoder {
+            // frozen spectra always get random phases, so they don't repeat
+            Some(phase_vocoder) if !frozen => {
+                if is_transient {
+                    phase_vocoder.reset();
+                }
+                phase_vocoder.propagate(&mut self.spectrum, analysis_hop, synthesis_hop);
             }
+            _ => self
+                .phase_randomizer
+                .randomize(&mut self.spectrum, is_transient),
+        }
+        // the DC and Nyquist bins of a real signal's spectrum are real
+        let nyquist = self.spectrum.len() - 1;
+        self.spectrum[0].im = 0.0;
+        self.spectrum[nyquist].im = 0.0;
+        self.inverse_fft
+            .process_with_scratch(&mut self.spectrum, &mut self.samples_buf, &mut self.scratch)
+            .unwrap();
+        for (sample, w) in self.samples_buf.iter_mut().zip(&self.window) {
+            *sample *= w / self.window_len as f32;
         }
     }
 }
@@ -0,0 +1,150 @@
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use rustfft::num_complex::Complex32;
+use std::sync::Arc;
+
+/// Quefrency below which the cepstrum is kept as the spectral envelope.
+///
+/// Shorter than the pitch period of any voice below about 650Hz, so
+/// individual harmonics are smoothed out of the envelope.
+const LIFTER_CUTOFF_SECS: f32 = 0.0015;
+/// Magnitudes are floored this far below the window's peak before taking
+/// logs, so silent bins don't drag the envelope down to minus infinity
+const MAGNITUDE_FLOOR: f32 = 1.0e-5;
+
+/// Spectral envelope correction which keeps formants in place through pitch shifts
+///
+/// Pitch shifting resamples the output, which moves the spectral envelope
+/// along with the partials. Before resynthesis, each bin is rescaled by the
+/// ratio between the envelope at the frequency it will be moved to and the
+/// envelope where it is, so after resampling the original envelope is back
+/// where it started. The envelope is estimated by cepstral liftering.
+pub struct FormantPreserver {
+    window_len: usize,
+    lifter_len: usize,
+    forward_fft: Arc<dyn RealToComplex<f32>>,
+    inverse_fft: Arc<dyn ComplexToReal<f32>>,
+    log_spectrum: Vec<Complex32>,
+    cepstrum: Vec<f32>,
+    scratch: Vec<Complex32>,
+    /// Natural log of the envelope's magnitude for each non-negative frequency bin
+    log_envelope: Vec<f32>,
+}
+
+impl FormantPreserver {
+    pub fn new(window_len: usize, sample_rate: u32) -> FormantPreserver {
+        let mut planner = RealFftPlanner::new();
+        let forward_fft = planner.plan_fft_forward(window_len);
+        let inverse_fft = planner.plan_fft_inverse(window_len);
+        let scratch_len = forward_fft
+            .get_scratch_len()
+            .max(inverse_fft.get_scratch_len());
+        FormantPreserver {
+            window_len,
+            lifter_len: ((sample_rate as f32 * LIFTER_CUTOFF_SECS) as usize)
+                .clamp(1, window_len / 2),
+            log_spectrum: forward_fft.make_output_vec(),
+            cepstrum: forward_fft.make_input_vec(),
+            scratch: vec![Complex32::new(0.0, 0.0); scratch_len],
+            forward_fft,
+            inverse_fft,
+            log_envelope: vec![0.0; window_len / 2 + 1],
+        }
+    }
+
+    /// Rescale the non-negative frequency half of a spectrum in place so
+    /// that once its frequencies are multiplied by `pitch`, its spectral
+    /// envelope is where it started.
+    pub fn correct(&mut self, spectrum: &mut [Complex32], pitch: f32) {
+        debug_assert!(spectrum.len() == self.log_envelope.len());
+        if pitch == 1.0 {
+            return;
+        }
+        self.estimate_envelope(spectrum);
+        for (k, bin) in spectrum.iter_mut().enumerate() {
+            *bin *= (self.log_envelope_at(k as f32 * pitch) - self.log_envelope[k]).exp();
+        }
+    }
+
+    /// Estimate the log envelope of `spectrum` by keeping only the low
+    /// quefrencies of its real cepstrum
+    fn estimate_envelope(&mut self, spectrum: &[Complex32]) {
+        let peak = spectrum.iter().fold(0.0f32, |max, bin| max.max(bin.norm()));
+        let floor = (peak * MAGNITUDE_FLOOR).max(f32::MIN_POSITIVE);
+        for (log_bin, bin) in self.log_spectrum.iter_mut().zip(spectrum) {
+            *log_bin = Complex32::new(bin.norm().max(floor).ln(), 0.0);
+        }
+        // the log magnitude is real and even, so its cepstrum is too
+        self.inverse_fft
+            .process_with_scratch(
+                &mut self.log_spectrum,
+                &mut self.cepstrum,
+                &mut self.scratch,
+            )
+            .unwrap();
+        for n in self.lifter_len..=self.window_len - self.lifter_len {
+            self.cepstrum[n] = 0.0;
+        }
+        self.forward_fft
+            .process_with_scratch(
+                &mut self.cepstrum,
+                &mut self.log_spectrum,
+                &mut self.scratch,
+            )
+            .unwrap();
+        for (log_magnitude, c) in self.log_envelope.iter_mut().zip(&self.log_spectrum) {
+            *log_magnitude = c.re / self.window_len as f32;
+        }
+    }
+
+    /// The log envelope at a fractional bin, held beyond the Nyquist frequency
+    fn log_envelope_at(&self, bin: f32) -> f32 {
+        let last = self.log_envelope.len() - 1;
+        if bin >= last as f32 {
+            return self.log_envelope[last];
+        }
+        let index = bin as usize;
+        let frac = bin - index as f32;
+        self.log_envelope[index] + (self.log_envelope[index + 1] - self.log_envelope[index]) * frac
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn unit_pitch_leaves_spectrum_alone() {
+        let mut preserver = FormantPreserver::new(8, 44100);
+        let original: Vec<Complex32> = (0..5).map(|k| Complex32::new(k as f32, 1.0)).collect();
+        let mut spectrum = original.clone();
+        preserver.correct(&mut spectrum, 1.0);
+        assert_eq!(spectrum, original);
+    }
+
+    #[test]
+    fn flat_spectrum_has_flat_envelope() {
+        let mut preserver = FormantPreserver::new(64, 44100);
+        let mut spectrum = vec![Complex32::new(0.5, 0.0); 33];
+        preserver.correct(&mut spectrum, 1.5);
+        for bin in spectrum {
+            assert_almost_eq(bin.norm(), 0.5);
+        }
+    }
+
+    #[test]
+    fn envelope_is_moved_by_inverse_of_pitch() {
+        // a smooth bump in the envelope centered on bin 16
+        let window_len = 128;
+        let mut spectrum: Vec<Complex32> = (0..=window_len / 2)
+            .map(|k| Complex32::new((-((k as f32 - 16.0) / 6.0).powi(2)).exp() + 0.1, 0.0))
+            .collect();
+        let mut preserver = FormantPreserver::new(window_len, 44100);
+        preserver.correct(&mut spectrum, 2.0);
+        let peak = (0..=window_len / 2)
+            .max_by(|a, b| spectrum[*a].norm().total_cmp(&spectrum[*b].norm()))
+            .unwrap();
+        // once frequencies are doubled, bin 8 lands on the original peak
+        assert_eq!(peak, 8);
+    }
+}
@@ -1,42 +1,98 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use fwatch::{BasicTarget, Transition, Watcher};
 use libloading::{Library, Symbol};
+use sha2::{Digest, Sha256};
+use std::env::{self, consts::DLL_SUFFIX};
 use std::ffi::CString;
+use std::fs::{self, DirEntry};
+use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tempfile;
 
 const WATCHER_POLL_DUR: Duration = Duration::from_millis(100);
 
+/// Compile the kernel at `path`, which is either a rust file or a cargo
+/// project directory, and again whenever anything it's built from changes.
 pub fn hotswap(path: PathBuf) -> Result<Receiver<Library>> {
     let (sender, receiver) = unbounded::<Library>();
+    let target_dir = target_dir(&path);
 
-    attempt_lib_update(&path, &sender);
-
-    let mut watcher: Watcher<BasicTarget> = Watcher::new();
-    watcher.add_target(BasicTarget::new(&path));
+    let mut watched = watched_paths(&path, &target_dir);
+    let mut last_modified = latest_modification_of(&watched);
+    attempt_lib_update(&path, &target_dir, &sender);
 
     thread::spawn(move || loop {
-        for event in watcher.watch() {
-            match event {
-                Transition::Modified => attempt_lib_update(&path, &sender),
-                _ => {}
+        // what the kernel is built from can change with each build
+        let built_from = watched_paths(&path, &target_dir);
+        if built_from != watched {
+            watched = built_from;
+            last_modified = latest_modification_of(&watched);
+        }
+        loop {
+            thread::sleep(WATCHER_POLL_DUR);
+            let modified = latest_modification_of(&watched);
+            if modified != last_modified {
+                last_modified = modified;
+                break;
             }
         }
-        thread::sleep(WATCHER_POLL_DUR);
+        attempt_lib_update(&path, &target_dir, &sender)
     });
 
     Ok(receiver)
 }
 
-fn attempt_lib_update(src_path: &Path, lib_sender: &Sender<Library>) {
-    let library = match compile(&src_path) {
+/// The kernel at `path` and the files outside it that it's built from
+fn watched_paths(path: &Path, target_dir: &Path) -> Vec<PathBuf> {
+    let mut watched = vec![path.to_path_buf()];
+    watched.extend(outside_sources(path, target_dir).unwrap_or_default());
+    watched
+}
+
+fn latest_modification_of(paths: &[PathBuf]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .filter_map(|path| latest_modification(path))
+        .max()
+}
+
+/// When anything at `path` was last modified, looking through directories
+/// recursively so removed files count too. Build output and hidden files are
+/// ignored.
+fn latest_modification(path: &Path) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+    fs::read_dir(path)
+        .ok()?
+        .flatten()
+        .filter(is_source)
+        .filter_map(|entry| latest_modification(&entry.path()))
+        .chain(metadata.modified().ok())
+        .max()
+}
+
+/// Whether a directory entry is part of a kernel's source, rather than
+/// build output or something hidden
+fn is_source(entry: &DirEntry) -> bool {
+    let name = entry.file_name();
+    name != "target" && !name.to_string_lossy().starts_with('.')
+}
+
+fn attempt_lib_update(src_path: &Path, target_dir: &Path, lib_sender: &Sender<Library>) {
+    let kernel_cache_dir = cache_dir().map(|dir| dir.join("kernels"));
+    let library = match compile(src_path, target_dir, kernel_cache_dir.as_deref()) {
         Ok(lib) => lib,
-        Err(_e) => {
-            warn!("Failed to compile library for file {:?}", &src_path);
+        Err(e) => {
+            warn!(
+                "Failed to compile library for file {:?}: {:#}",
+                &src_path, e
+            );
             return;
         }
     };
@@ -49,27 +105,309 @@ fn attempt_lib_update(src_path: &Path, lib_sender: &Sender<Library>) {
     }
 }
 
-fn compile(path: &Path) -> Result<Library> {
+/// The rocoder's directory in the XDG cache directory, which holds compiled
+/// kernels in `kernels` and the target dirs of cargo projects in `targets`
+fn cache_dir() -> Option<PathBuf> {
+    let cache_home = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("rocoder"))
+}
+
+/// Where the cargo project at `path` is built. It's the same for every
+/// channel, so an edit is only built once, and cargo keeps the builds from
+/// running into each other.
+fn target_dir(path: &Path) -> PathBuf {
+    let project = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let project_hash = hex(&Sha256::digest(project.to_string_lossy().as_bytes()));
+    cache_dir()
+        .unwrap_or_else(|| env::temp_dir().join("rocoder"))
+        .join("targets")
+        .join(project_hash)
+}
+
+/// Check that the kernel at `path` can be built without changing it: cargo
+/// projects need a Cargo.lock, which would otherwise be written into them
+pub fn check_kernel_source(path: &Path) -> Result<()> {
+    if path.is_dir() && !path.join("Cargo.lock").exists() {
+        bail!(
+            "kernel project {} has no Cargo.lock; run `cargo generate-lockfile` in it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Compile and load the kernel at `path`. Cargo project directories are
+/// built into `target_dir`, while rust files are compiled on their own.
+///
+/// With a `cache_dir`, compiled kernels are kept there named by a hash of
+/// their source, the compiler version and flags, and reused instead of
+/// compiling the same thing again.
+pub fn compile(path: &Path, target_dir: &Path, cache_dir: Option<&Path>) -> Result<Library> {
     if cfg!(target_os = "windows") {
         // this definitely _can_ be done, but the code would be different here
         // and I don't have a windows machine to develop on
         panic!("hotswapping is not supported on windows");
     }
-    let build_target = tempfile::Builder::new().suffix(".so").tempfile()?;
-    let build_target_path = build_target.path().as_os_str();
+    // dependency versions are pinned by the lockfile, so the hash covers them
+    check_kernel_source(path)?;
+    let cached = cached_path(path, target_dir, cache_dir)?;
+    // libraries are only loaded once per path, so each load is from a copy
+    // with its own statics
+    let build_target = tempfile::Builder::new().suffix(DLL_SUFFIX).tempfile()?;
+    if let Some(cached) = cached.as_ref().filter(|cached| cached.is_file()) {
+        info!("Using cached kernel {}", cached.display());
+        fs::copy(cached, build_target.path())?;
+        return Ok(unsafe { Library::new(build_target.path())? });
+    }
+    if path.is_dir() {
+        compile_cargo_project(path, target_dir, build_target.path())?;
+    } else {
+        compile_file(path, build_target.path())?;
+    }
+    // a cargo project's path dependencies are only known once it's been built
+    let cached = match cached {
+        Some(cached) if !path.is_dir() => Some(cached),
+        _ => cached_path(path, target_dir, cache_dir)?,
+    };
+    if let Some(cached) = cached {
+        if let Err(e) = store_in_cache(build_target.path(), &cached) {
+            warn!("Failed to cache kernel in {}: {:#}", cached.display(), e);
+        }
+    }
+    Ok(unsafe { Library::new(build_target.path())? })
+}
+
+const RUSTC_ARGS: &[&str] = &[
+    "--color",
+    "always",
+    "-A",
+    "warnings",
+    "--codegen",
+    "opt-level=3",
+    "--edition",
+    "2021",
+    // kernel file names needn't be valid crate names
+    "--crate-name",
+    "kernel",
+    "--crate-type",
+    "cdylib",
+];
+const CARGO_ARGS: &[&str] = &[
+    "build",
+    "--release",
+    "--offline",
+    "--locked",
+    "--quiet",
+    "--color",
+    "always",
+];
+const CARGO_RUSTFLAGS: &str = "-A warnings";
+
+fn compile_file(path: &Path, output: &Path) -> Result<()> {
     let compile_output = Command::new("rustc")
-        .arg("--color")
-        .arg("always")
-        .arg("-A")
-        .arg("warnings")
-        .arg("--codegen")
-        .arg("opt-level=3")
-        .arg("--crate-type")
-        .arg("dylib")
+        .args(RUSTC_ARGS)
         .arg(path)
         .arg("-o")
-        .arg(build_target_path)
+        .arg(output)
+        .output()?;
+    show_compile_output(compile_output, "rustc")
+}
+
+fn compile_cargo_project(path: &Path, target_dir: &Path, output: &Path) -> Result<()> {
+    let compile_output = Command::new("cargo")
+        .args(CARGO_ARGS)
+        .arg("--manifest-path")
+        .arg(path.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(target_dir)
+        .env("RUSTFLAGS", CARGO_RUSTFLAGS)
         .output()?;
+    show_compile_output(compile_output, "cargo")?;
+    let built = newest_library(&target_dir.join("release"))
+        .context("cargo didn't build a cdylib; is the crate-type set?")?;
+    fs::copy(built, output)?;
+    Ok(())
+}
+
+/// The most recently built dynamic library in `dir`
+fn newest_library(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(DLL_SUFFIX))
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+        .map(|entry| entry.path())
+}
+
+/// Where the kernel at `path` is kept in `cache_dir`, if there is one and
+/// everything the kernel is built from is known
+fn cached_path(
+    path: &Path,
+    target_dir: &Path,
+    cache_dir: Option<&Path>,
+) -> Result<Option<PathBuf>> {
+    let (cache_dir, outside_sources) = match (cache_dir, outside_sources(path, target_dir)) {
+        (Some(cache_dir), Some(outside_sources)) => (cache_dir, outside_sources),
+        _ => return Ok(None),
+    };
+    let hash = build_hash(path, &outside_sources)?;
+    Ok(Some(cache_dir.join(format!("{}{}", hash, DLL_SUFFIX))))
+}
+
+/// The files outside `path` that the kernel there is built from, as the
+/// compiler lists them: the modules and included files of a rust file, or
+/// the path dependencies of a cargo project. A project's are only known
+/// from its last build, so this is None if it hasn't been built yet or the
+/// file doesn't compile.
+fn outside_sources(path: &Path, target_dir: &Path) -> Option<Vec<PathBuf>> {
+    if path.is_dir() {
+        let dep_info = newest_library(&target_dir.join("release"))?.with_extension("d");
+        let project = fs::canonicalize(path).ok()?;
+        let cargo_home = env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")));
+        let sources = dep_info_sources(&dep_info).ok()?;
+        Some(
+            sources
+                .into_iter()
+                .filter(|source| !source.starts_with(&project))
+                // registry and git dependencies are pinned by the lockfile
+                .filter(|source| {
+                    !cargo_home
+                        .as_ref()
+                        .is_some_and(|home| source.starts_with(home))
+                })
+                .collect(),
+        )
+    } else {
+        let dep_info = tempfile::Builder::new().suffix(".d").tempfile().ok()?;
+        let output = Command::new("rustc")
+            .args(RUSTC_ARGS)
+            .arg("--emit")
+            .arg(format!("dep-info={}", dep_info.path().display()))
+            .arg(path)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let sources = dep_info_sources(dep_info.path()).ok()?;
+        Some(
+            sources
+                .into_iter()
+                .filter(|source| source != path)
+                .collect(),
+        )
+    }
+}
+
+/// The files a dep-info file written by rustc or cargo lists as its
+/// target's dependencies
+fn dep_info_sources(dep_info: &Path) -> Result<Vec<PathBuf>> {
+    let contents = fs::read_to_string(dep_info)?;
+    let deps = match contents
+        .lines()
+        .next()
+        .and_then(|line| line.split_once(": "))
+    {
+        Some((_, deps)) => deps,
+        None => return Ok(vec![]),
+    };
+    let mut sources = vec![];
+    let mut source = String::new();
+    let mut chars = deps.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // spaces in paths are escaped
+            '\\' => source.extend(chars.next()),
+            ' ' => {
+                if !source.is_empty() {
+                    sources.push(PathBuf::from(mem::take(&mut source)));
+                }
+            }
+            c => source.push(c),
+        }
+    }
+    if !source.is_empty() {
+        sources.push(PathBuf::from(source));
+    }
+    Ok(sources)
+}
+
+/// A hash of everything that goes into compiling the kernel at `path`: its
+/// source and the `outside_sources` it's built from, the compiler's version
+/// and the flags it's compiled with
+fn build_hash(path: &Path, outside_sources: &[PathBuf]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    if path.is_dir() {
+        for tool in ["cargo", "rustc"] {
+            hasher.update(Command::new(tool).arg("-vV").output()?.stdout);
+        }
+        hasher.update(format!("{:?} {}", CARGO_ARGS, CARGO_RUSTFLAGS));
+        hash_dir(&mut hasher, path, Path::new(""))?;
+    } else {
+        hasher.update(Command::new("rustc").arg("-vV").output()?.stdout);
+        hasher.update(format!("{:?}", RUSTC_ARGS));
+        hasher.update(fs::read(path)?);
+    }
+    // named by where they are next to the kernel, so they can move together
+    let base = fs::canonicalize(path)?;
+    let base = base.parent().unwrap_or(Path::new(""));
+    for source in outside_sources {
+        let source = fs::canonicalize(source)?;
+        let name = source.strip_prefix(base).unwrap_or(&source);
+        hash_file(&mut hasher, name, &fs::read(&source)?);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hash the names and contents of the source files in `dir`, in a stable
+/// order. `relative` is where `dir` is in the project.
+fn hash_dir(hasher: &mut Sha256, dir: &Path, relative: &Path) -> Result<()> {
+    let mut entries: Vec<DirEntry> = fs::read_dir(dir)?
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(is_source)
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            hash_dir(hasher, &entry.path(), &relative)?;
+        } else {
+            hash_file(hasher, &relative, &fs::read(entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(hasher: &mut Sha256, name: &Path, contents: &[u8]) {
+    // lengths keep names and contents from running into each other
+    for part in [name.to_string_lossy().as_bytes(), contents] {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+}
+
+/// Copy a compiled kernel into the cache, so it's never seen half-written
+fn store_in_cache(library: &Path, cached: &Path) -> Result<()> {
+    let cache_dir = cached.parent().unwrap();
+    fs::create_dir_all(cache_dir)?;
+    let partial = tempfile::NamedTempFile::new_in(cache_dir)?;
+    fs::copy(library, partial.path())?;
+    partial.persist(cached)?;
+    Ok(())
+}
+
+/// Print whatever the compiler had to say, and fail if it failed
+fn show_compile_output(compile_output: Output, compiler: &str) -> Result<()> {
     if !compile_output.stderr.is_empty() {
         println!("=========================================================");
         println!("================rust compilation failed==================");
@@ -80,11 +418,256 @@ fn compile(path: &Path) -> Result<Library> {
         println!("=========================================================");
     }
     if !compile_output.status.success() {
-        bail!("rustc compilation failed");
+        bail!("{} compilation failed", compiler);
     }
-    Ok(unsafe { Library::new(&build_target_path)? })
+    Ok(())
 }
 
 pub fn load_fn<'lib, T>(library: &'lib Library, symbol: &[u8]) -> Result<Symbol<'lib, T>> {
     unsafe { Ok(library.get(CString::new(symbol)?.as_bytes())?) }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A cargo project for a kernel exporting `ANSWER`, from one of its modules
+    fn answer_project() -> tempfile::TempDir {
+        let project = tempfile::tempdir().unwrap();
+        fs::write(
+            project.path().join("Cargo.toml"),
+            "[package]\nname = \"test-kernel\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [lib]\ncrate-type = [\"cdylib\"]\n\n[workspace]\n",
+        )
+        .unwrap();
+        fs::create_dir(project.path().join("src")).unwrap();
+        fs::write(
+            project.path().join("src/helpers.rs"),
+            "pub const ANSWER: u32 = 42;",
+        )
+        .unwrap();
+        fs::write(
+            project.path().join("src/lib.rs"),
+            "mod helpers;\n#[no_mangle]\npub static ANSWER: u32 = helpers::ANSWER;",
+        )
+        .unwrap();
+        project
+    }
+
+    #[test]
+    fn cargo_projects_are_built_and_loaded() {
+        let project = answer_project();
+        let lock_output = Command::new("cargo")
+            .args(["generate-lockfile", "--offline", "--quiet"])
+            .current_dir(project.path())
+            .output()
+            .unwrap();
+        assert!(lock_output.status.success());
+        let target_dir = tempfile::tempdir().unwrap();
+        let library = compile(project.path(), target_dir.path(), None).unwrap();
+        assert_eq!(answer(&library), 42);
+    }
+
+    #[test]
+    fn cargo_projects_without_a_lockfile_are_left_alone() {
+        let project = answer_project();
+        let target_dir = tempfile::tempdir().unwrap();
+        assert!(compile(project.path(), target_dir.path(), None).is_err());
+        assert!(!project.path().join("Cargo.lock").exists());
+    }
+
+    #[test]
+    fn channels_share_a_target_dir() {
+        let project = answer_project();
+        assert_eq!(target_dir(project.path()), target_dir(project.path()));
+        let other_project = answer_project();
+        assert_ne!(target_dir(project.path()), target_dir(other_project.path()));
+    }
+
+    #[test]
+    fn changes_deep_in_a_directory_are_noticed() {
+        let project = tempfile::tempdir().unwrap();
+        fs::create_dir_all(project.path().join("src/effects")).unwrap();
+        fs::write(project.path().join("Cargo.toml"), "").unwrap();
+        let before = latest_modification(project.path());
+        thread::sleep(Duration::from_millis(20));
+        fs::write(project.path().join("src/effects/blur.rs"), "").unwrap();
+        assert!(latest_modification(project.path()) > before);
+        // build output doesn't count
+        fs::create_dir(project.path().join("target")).unwrap();
+        let after = latest_modification(project.path());
+        thread::sleep(Duration::from_millis(20));
+        fs::write(project.path().join("target/libkernel.so"), "").unwrap();
+        assert_eq!(latest_modification(project.path()), after);
+    }
+
+    /// A rust file exporting `ANSWER`
+    fn answer_src(answer: u32) -> tempfile::NamedTempFile {
+        let file = tempfile::Builder::new().suffix(".rs").tempfile().unwrap();
+        fs::write(
+            file.path(),
+            format!("#[no_mangle] pub static ANSWER: u32 = {};", answer),
+        )
+        .unwrap();
+        file
+    }
+
+    fn answer(library: &Library) -> u32 {
+        let answer: *const u32 = *load_fn(library, b"ANSWER").unwrap();
+        unsafe { *answer }
+    }
+
+    #[test]
+    fn compiled_kernels_are_cached_by_content() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let src = answer_src(42);
+        let library = compile(src.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 42);
+        let cached: Vec<PathBuf> = fs::read_dir(cache_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(cached.len(), 1);
+
+        // swap in a different library to tell whether the cache is used
+        compile_file(answer_src(7).path(), &cached[0]).unwrap();
+        let same_src = answer_src(42);
+        let library = compile(same_src.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 7);
+        let other_src = answer_src(43);
+        let library = compile(other_src.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 43);
+    }
+
+    #[test]
+    fn cached_kernels_are_loaded_afresh_each_time() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let src = tempfile::Builder::new().suffix(".rs").tempfile().unwrap();
+        fs::write(
+            src.path(),
+            "static mut CALLS: u32 = 0;
+            #[no_mangle]
+            pub extern \"C\" fn count_call() -> u32 {
+                unsafe {
+                    CALLS += 1;
+                    CALLS
+                }
+            }",
+        )
+        .unwrap();
+        let count_call = |library: &Library| {
+            let count_call = *load_fn::<extern "C" fn() -> u32>(library, b"count_call").unwrap();
+            count_call()
+        };
+        let compiled = compile(src.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        let first = compile(src.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        let second = compile(src.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(count_call(&compiled), 1);
+        assert_eq!(count_call(&first), 1);
+        assert_eq!(count_call(&second), 1);
+        assert_eq!(count_call(&first), 2);
+    }
+
+    #[test]
+    fn modules_next_to_kernel_files_are_part_of_their_build() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let src_dir = tempfile::tempdir().unwrap();
+        let kernel = src_dir.path().join("kernel.rs");
+        fs::write(
+            &kernel,
+            "mod helpers;\n#[no_mangle]\npub static ANSWER: u32 = helpers::ANSWER;",
+        )
+        .unwrap();
+        let helpers = src_dir.path().join("helpers.rs");
+        fs::write(&helpers, "pub const ANSWER: u32 = 42;").unwrap();
+        let library = compile(&kernel, target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 42);
+        assert_eq!(
+            watched_paths(&kernel, target_dir.path()),
+            vec![kernel.clone(), helpers.clone()]
+        );
+
+        fs::write(&helpers, "pub const ANSWER: u32 = 43;").unwrap();
+        let library = compile(&kernel, target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 43);
+    }
+
+    #[test]
+    fn path_dependencies_are_part_of_a_projects_build() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let project = answer_project();
+        let dependency = tempfile::tempdir().unwrap();
+        fs::write(
+            dependency.path().join("Cargo.toml"),
+            "[package]\nname = \"answers\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::create_dir(dependency.path().join("src")).unwrap();
+        let dependency_src = dependency.path().join("src/lib.rs");
+        fs::write(&dependency_src, "pub const ANSWER: u32 = 42;").unwrap();
+        let mut manifest = fs::read_to_string(project.path().join("Cargo.toml")).unwrap();
+        manifest.push_str(&format!(
+            "\n[dependencies]\nanswers = {{ path = {:?} }}\n",
+            dependency.path()
+        ));
+        fs::write(project.path().join("Cargo.toml"), manifest).unwrap();
+        fs::write(
+            project.path().join("src/helpers.rs"),
+            "pub const ANSWER: u32 = answers::ANSWER;",
+        )
+        .unwrap();
+        let lock_output = Command::new("cargo")
+            .args(["generate-lockfile", "--offline", "--quiet"])
+            .current_dir(project.path())
+            .output()
+            .unwrap();
+        assert!(lock_output.status.success());
+        let library = compile(project.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 42);
+        assert!(watched_paths(project.path(), target_dir.path())
+            .contains(&fs::canonicalize(&dependency_src).unwrap()));
+
+        fs::write(&dependency_src, "pub const ANSWER: u32 = 43;").unwrap();
+        let library = compile(project.path(), target_dir.path(), Some(cache_dir.path())).unwrap();
+        assert_eq!(answer(&library), 43);
+    }
+
+    #[test]
+    fn dep_info_sources_unescape_spaces() {
+        let dep_info = tempfile::Builder::new().suffix(".d").tempfile().unwrap();
+        fs::write(
+            dep_info.path(),
+            "/out/libkernel.so: /src/lib.rs /my\\ kernels/lib.rs\n\n/src/lib.rs:\n",
+        )
+        .unwrap();
+        assert_eq!(
+            dep_info_sources(dep_info.path()).unwrap(),
+            vec![
+                PathBuf::from("/src/lib.rs"),
+                PathBuf::from("/my kernels/lib.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn build_hash_covers_every_source_file() {
+        let project = tempfile::tempdir().unwrap();
+        fs::create_dir_all(project.path().join("src/effects")).unwrap();
+        fs::write(project.path().join("Cargo.toml"), "").unwrap();
+        fs::write(project.path().join("src/effects/blur.rs"), "").unwrap();
+        let original = build_hash(project.path(), &[]).unwrap();
+        fs::write(project.path().join("src/effects/blur.rs"), "// edited").unwrap();
+        let edited = build_hash(project.path(), &[]).unwrap();
+        assert_ne!(edited, original);
+        fs::write(project.path().join("src/effects/blur.rs"), "").unwrap();
+        assert_eq!(build_hash(project.path(), &[]).unwrap(), original);
+        // build output doesn't count
+        fs::create_dir(project.path().join("target")).unwrap();
+        fs::write(project.path().join("target/libkernel.so"), "").unwrap();
+        assert_eq!(build_hash(project.path(), &[]).unwrap(), original);
+    }
+}
@@ -0,0 +1,576 @@
+use crate::fft::KernelClock;
+use crate::hotswapper;
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::Receiver;
+use libloading::Library;
+use rustfft::num_complex::Complex32;
+use std::ffi::c_void;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the kernel interface. Kernels export the version they were
+/// written for as `ROCODER_KERNEL_ABI_VERSION`, and are only loaded if it matches.
+pub const KERNEL_ABI_VERSION: u32 = 1;
+
+/// The most state a kernel's `save_state` can hand over to the next version
+const MAX_SAVED_STATE_LEN: usize = 64 * 1024 * 1024;
+
+/// What a kernel is told about the window it's processing
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KernelContext {
+    pub sample_rate: u32,
+    /// Index of the audio channel being stretched
+    pub channel: u32,
+    /// Samples in each analysis window
+    pub window_len: u64,
+    /// Index of the window in the channel, counting from 0
+    pub window: u64,
+    /// Milliseconds on the kernel clock, for time-varying effects
+    pub elapsed_ms: u64,
+    /// Seconds of output audio preceding the window
+    pub audio_time: f64,
+    /// Frequency spacing of the bins in Hz, so bin `k` is at `k * bin_hz`
+    pub bin_hz: f32,
+}
+
+/// A stateless kernel's `apply` function, which processes the non-negative
+/// frequency bins of one window in place and returns 0, or anything else if
+/// it failed.
+///
+/// Kernels have their own copy of the standard library, so they can't
+/// unwind into the host and must catch their own panics.
+type ApplyFn = unsafe extern "C" fn(&KernelContext, *mut Complex32, usize) -> i32;
+/// Creates a stateful kernel's state, or returns null if it failed
+type InitFn = unsafe extern "C" fn(&KernelContext) -> *mut c_void;
+/// `apply` for stateful kernels, which are also given their state
+type ProcessFn = unsafe extern "C" fn(*mut c_void, &KernelContext, *mut Complex32, usize) -> i32;
+/// Frees a stateful kernel's state
+type DropFn = unsafe extern "C" fn(*mut c_void);
+/// Serializes a kernel's state into a buffer of the given capacity,
+/// returning how long it is. Nothing is written if it doesn't fit.
+type SaveStateFn = unsafe extern "C" fn(*mut c_void, *mut u8, usize) -> usize;
+/// Restores a kernel's state from another version of the kernel, returning
+/// 0, or anything else if it failed
+type LoadStateFn = unsafe extern "C" fn(*mut c_void, *const u8, usize) -> i32;
+
+/// How a kernel processes windows
+enum Entry {
+    Apply(ApplyFn),
+    Process(ProcessFn),
+}
+
+/// A kernel library, checked against the kernel interface version, along
+/// with its state if it has any
+struct LoadedKernel {
+    entry: Entry,
+    state: *mut c_void,
+    drop_state: Option<DropFn>,
+    save_state: Option<SaveStateFn>,
+    load_state: Option<LoadStateFn>,
+    // declared last so it outlives the function pointers
+    _library: Library,
+}
+
+// a kernel's state is only ever used through a mutable reference, by the
+// thread processing its channel
+unsafe impl Send for LoadedKernel {}
+unsafe impl Sync for LoadedKernel {}
+
+impl LoadedKernel {
+    fn load(library: Library, context: &KernelContext) -> Result<LoadedKernel> {
+        let version = unsafe {
+            let version: *const u32 = *hotswapper::load_fn(&library, b"ROCODER_KERNEL_ABI_VERSION")
+                .context("kernel doesn't export ROCODER_KERNEL_ABI_VERSION")?;
+            *version
+        };
+        if version != KERNEL_ABI_VERSION {
+            bail!(
+                "kernel is written for interface version {}, but this is version {}",
+                version,
+                KERNEL_ABI_VERSION
+            );
+        }
+        let entry = match (
+            optional_fn(&library, b"process"),
+            optional_fn(&library, b"apply"),
+        ) {
+            (Some(process), _) => Entry::Process(process),
+            (None, Some(apply)) => Entry::Apply(apply),
+            (None, None) => bail!("kernel exports neither apply nor process"),
+        };
+        let state = match optional_fn::<InitFn>(&library, b"init") {
+            None if matches!(entry, Entry::Process(_)) => {
+                bail!("kernel exports process without init to create its state")
+            }
+            Some(init) => {
+                let state = unsafe { init(context) };
+                if state.is_null() {
+                    bail!("kernel's init failed");
+                }
+                state
+            }
+            None => ptr::null_mut(),
+        };
+        Ok(LoadedKernel {
+            entry,
+            state,
+            drop_state: optional_fn(&library, b"drop"),
+            save_state: optional_fn(&library, b"save_state"),
+            load_state: optional_fn(&library, b"load_state"),
+            _library: library,
+        })
+    }
+
+    /// Process one window's bins in place, returning the kernel's status
+    fn process(&mut self, context: &KernelContext, bins: &mut [Complex32]) -> i32 {
+        unsafe {
+            match self.entry {
+                Entry::Apply(apply) => apply(context, bins.as_mut_ptr(), bins.len()),
+                Entry::Process(process) => {
+                    process(self.state, context, bins.as_mut_ptr(), bins.len())
+                }
+            }
+        }
+    }
+
+    /// The kernel's state serialized by its `save_state` hook, if it has one
+    fn save_state(&mut self) -> Option<Vec<u8>> {
+        if self.state.is_null() {
+            return None;
+        }
+        let save_state = self.save_state?;
+        let mut buf = vec![];
+        // the first call finds out how big the state is, and the second gets it
+        for _ in 0..2 {
+            let len = unsafe { save_state(self.state, buf.as_mut_ptr(), buf.len()) };
+            if len <= buf.len() {
+                buf.truncate(len);
+                return Some(buf);
+            }
+            if len > MAX_SAVED_STATE_LEN {
+                warn!(
+                    "Kernel state not carried over: save_state wants {} bytes, over the limit of {}",
+                    len, MAX_SAVED_STATE_LEN
+                );
+                return None;
+            }
+            buf.resize(len, 0);
+        }
+        warn!("Kernel state not carried over: save_state didn't fit the length it asked for");
+        None
+    }
+
+    /// Take over state saved by another version of the kernel, if this one
+    /// has a `load_state` hook
+    fn load_state(&mut self, saved: &[u8]) -> Result<()> {
+        if self.state.is_null() {
+            return Ok(());
+        }
+        if let Some(load_state) = self.load_state {
+            if unsafe { load_state(self.state, saved.as_ptr(), saved.len()) } != 0 {
+                bail!("kernel's load_state failed");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LoadedKernel {
+    fn drop(&mut self) {
+        if let Some(drop_state) = self.drop_state.filter(|_| !self.state.is_null()) {
+            unsafe { drop_state(self.state) };
+        }
+    }
+}
+
+/// A function the kernel may or may not export
+fn optional_fn<T: Copy>(library: &Library, symbol: &[u8]) -> Option<T> {
+    hotswapper::load_fn::<T>(library, symbol).ok().map(|f| *f)
+}
+
+/// A hot-swappable frequency kernel for one channel
+pub struct FrequencyKernel {
+    clock: KernelClock,
+    context: KernelContext,
+    library_recv: Receiver<Library>,
+    /// Kernels loaded so far, latest last, to fall back on if the latest fails
+    kernels: Vec<LoadedKernel>,
+    /// The spectrum as it was before the kernel, restored if it fails
+    backup: Vec<Complex32>,
+    /// Whether spectra are passed through untouched
+    bypassed: bool,
+}
+
+impl FrequencyKernel {
+    /// Compile the kernel at `src`, recompiling it whenever it changes
+    pub fn new(
+        src: PathBuf,
+        clock: KernelClock,
+        sample_rate: u32,
+        window_len: usize,
+        channel: usize,
+    ) -> Result<FrequencyKernel> {
+        Ok(FrequencyKernel::with_libraries(
+            hotswapper::hotswap(src)?,
+            clock,
+            sample_rate,
+            window_len,
+            channel,
+        ))
+    }
+
+    /// A kernel which swaps in each library received from `library_recv`
+    fn with_libraries(
+        library_recv: Receiver<Library>,
+        clock: KernelClock,
+        sample_rate: u32,
+        window_len: usize,
+        channel: usize,
+    ) -> FrequencyKernel {
+        FrequencyKernel {
+            clock,
+            context: KernelContext {
+                sample_rate,
+                channel: channel as u32,
+                window_len: window_len as u64,
+                window: 0,
+                elapsed_ms: 0,
+                audio_time: 0.0,
+                bin_hz: sample_rate as f32 / window_len as f32,
+            },
+            library_recv,
+            kernels: vec![],
+            backup: vec![Complex32::new(0.0, 0.0); window_len / 2 + 1],
+            bypassed: false,
+        }
+    }
+
+    /// Count windows from `window` next
+    pub fn seek(&mut self, window: usize) {
+        self.context.window = window as u64;
+    }
+
+    /// Pass spectra through untouched until un-bypassed. New versions of
+    /// the kernel are still swapped in meanwhile.
+    pub fn set_bypassed(&mut self, bypassed: bool) {
+        self.bypassed = bypassed;
+    }
+
+    /// Run the non-negative frequency half of a spectrum through the latest
+    /// kernel that doesn't fail. `audio_time` is how much output precedes it.
+    pub fn apply(&mut self, spectrum: &mut [Complex32], audio_time: Duration) {
+        debug_assert!(spectrum.len() == self.backup.len());
+        self.context.elapsed_ms = match self.clock {
+            KernelClock::WallClock => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            KernelClock::AudioTime => audio_time.as_millis() as u64,
+        };
+        self.context.audio_time = audio_time.as_secs_f64();
+        if let Ok(library) = self.library_recv.try_recv() {
+            self.swap_in(library);
+        }
+        if self.bypassed {
+            self.context.window += 1;
+            return;
+        }
+        if !self.kernels.is_empty() {
+            self.backup.copy_from_slice(spectrum);
+        }
+        while let Some(kernel) = self.kernels.last_mut() {
+            if kernel.process(&self.context, spectrum) == 0 {
+                break;
+            }
+            // make sure we dont keep using the new lib if it fails
+            warn!("kernel failed, retrying with last or noop.");
+            spectrum.copy_from_slice(&self.backup);
+            self.kernels.pop();
+        }
+        self.context.window += 1;
+    }
+
+    /// Load a newly compiled kernel, carrying over the current kernel's state
+    fn swap_in(&mut self, library: Library) {
+        let mut kernel = match LoadedKernel::load(library, &self.context) {
+            Ok(kernel) => kernel,
+            Err(e) => {
+                warn!("Not using new kernel: {:#}", e);
+                return;
+            }
+        };
+        if let Some(saved) = self.kernels.last_mut().and_then(LoadedKernel::save_state) {
+            if let Err(e) = kernel.load_state(&saved) {
+                warn!("New kernel starts without the last one's state: {:#}", e);
+            }
+        }
+        info!("Got new kernel");
+        self.kernels.push(kernel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crossbeam_channel::{unbounded, Sender};
+    use std::io::Write;
+    use test_case::test_case;
+
+    const CONTEXT_DEF: &str = "
+        #[repr(C)]
+        pub struct KernelContext {
+            pub sample_rate: u32,
+            pub channel: u32,
+            pub window_len: u64,
+            pub window: u64,
+            pub elapsed_ms: u64,
+            pub audio_time: f64,
+            pub bin_hz: f32,
+        }
+    ";
+
+    /// A kernel which writes what it's told into the first bins
+    const REPORTING_KERNEL: &str = "
+        #[no_mangle]
+        pub extern \"C\" fn apply(context: &KernelContext, bins: *mut [f32; 2], len: usize) -> i32 {
+            let bins = unsafe { std::slice::from_raw_parts_mut(bins, len) };
+            bins[0] = [context.sample_rate as f32, context.channel as f32];
+            bins[1] = [context.window_len as f32, context.window as f32];
+            bins[2] = [context.elapsed_ms as f32, context.audio_time as f32];
+            bins[3] = [context.bin_hz, len as f32];
+            0
+        }
+    ";
+
+    /// A kernel which counts the windows it has processed in its state, and
+    /// writes the count and `version` into the first bin
+    fn counting_kernel(version: u32, save_state: bool, load_state: bool) -> String {
+        let mut src = format!(
+            "
+            #[no_mangle]
+            pub static ROCODER_KERNEL_ABI_VERSION: u32 = {};
+            {}
+            pub struct Count {{
+                windows: u32,
+            }}
+            #[no_mangle]
+            pub extern \"C\" fn init(_: &KernelContext) -> *mut Count {{
+                Box::into_raw(Box::new(Count {{ windows: 0 }}))
+            }}
+            #[no_mangle]
+            pub extern \"C\" fn process(
+                count: &mut Count,
+                _: &KernelContext,
+                bins: *mut [f32; 2],
+                len: usize,
+            ) -> i32 {{
+                let bins = unsafe {{ std::slice::from_raw_parts_mut(bins, len) }};
+                count.windows += 1;
+                bins[0] = [count.windows as f32, {}.0];
+                0
+            }}
+            #[no_mangle]
+            pub extern \"C\" fn drop(count: *mut Count) {{
+                unsafe {{ Box::from_raw(count) }};
+            }}
+            ",
+            KERNEL_ABI_VERSION, CONTEXT_DEF, version
+        );
+        if save_state {
+            src.push_str(
+                "
+                #[no_mangle]
+                pub extern \"C\" fn save_state(count: &Count, buf: *mut u8, capacity: usize) -> usize {
+                    let bytes = count.windows.to_le_bytes();
+                    if capacity >= bytes.len() {
+                        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len()) };
+                    }
+                    bytes.len()
+                }
+                ",
+            );
+        }
+        if load_state {
+            src.push_str(
+                "
+                #[no_mangle]
+                pub extern \"C\" fn load_state(count: &mut Count, buf: *const u8, len: usize) -> i32 {
+                    let bytes = unsafe { std::slice::from_raw_parts(buf, len) };
+                    match bytes.try_into() {
+                        Ok(bytes) => {
+                            count.windows = u32::from_le_bytes(bytes);
+                            0
+                        }
+                        Err(_) => 1,
+                    }
+                }
+                ",
+            );
+        }
+        src
+    }
+
+    fn compile(src: &str) -> Library {
+        let mut file = tempfile::Builder::new().suffix(".rs").tempfile().unwrap();
+        file.write_all(src.as_bytes()).unwrap();
+        hotswapper::compile(file.path(), &std::env::temp_dir(), None).unwrap()
+    }
+
+    /// A kernel which will swap in whatever is sent to it
+    fn kernel() -> (FrequencyKernel, Sender<Library>) {
+        let (tx, rx) = unbounded();
+        let kernel = FrequencyKernel::with_libraries(rx, KernelClock::AudioTime, 44100, 16, 1);
+        (kernel, tx)
+    }
+
+    #[test]
+    fn kernel_is_given_context_and_bins() {
+        let (mut kernel, tx) = kernel();
+        tx.send(compile(&format!(
+            "#[no_mangle] pub static ROCODER_KERNEL_ABI_VERSION: u32 = {};{}{}",
+            KERNEL_ABI_VERSION, CONTEXT_DEF, REPORTING_KERNEL
+        )))
+        .unwrap();
+        let mut spectrum = vec![Complex32::new(0.0, 0.0); 9];
+        kernel.apply(&mut spectrum, Duration::from_millis(1500));
+        kernel.apply(&mut spectrum, Duration::from_millis(2500));
+        assert_eq!(
+            spectrum[..4],
+            [
+                Complex32::new(44100.0, 1.0),
+                Complex32::new(16.0, 1.0),
+                Complex32::new(2500.0, 2.5),
+                Complex32::new(2756.25, 9.0),
+            ]
+        );
+    }
+
+    #[test_case("" ; "without a version")]
+    #[test_case("#[no_mangle] pub static ROCODER_KERNEL_ABI_VERSION: u32 = 0;" ; "with another version")]
+    fn kernel_with_wrong_version_is_not_used(version_def: &str) {
+        let (mut kernel, tx) = kernel();
+        tx.send(compile(&format!(
+            "{}{}{}",
+            version_def, CONTEXT_DEF, REPORTING_KERNEL
+        )))
+        .unwrap();
+        let mut spectrum = vec![Complex32::new(0.5, 0.0); 9];
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum, vec![Complex32::new(0.5, 0.0); 9]);
+    }
+
+    #[test]
+    fn failing_kernel_leaves_spectrum_alone() {
+        let (mut kernel, tx) = kernel();
+        tx.send(compile(&format!(
+            "#[no_mangle] pub static ROCODER_KERNEL_ABI_VERSION: u32 = {};{}
+            #[no_mangle]
+            pub extern \"C\" fn apply(_: &KernelContext, bins: *mut [f32; 2], len: usize) -> i32 {{
+                let bins = unsafe {{ std::slice::from_raw_parts_mut(bins, len) }};
+                bins[0] = [9.0, 9.0];
+                1
+            }}",
+            KERNEL_ABI_VERSION, CONTEXT_DEF
+        )))
+        .unwrap();
+        let mut spectrum = vec![Complex32::new(0.5, 0.0); 9];
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum, vec![Complex32::new(0.5, 0.0); 9]);
+        assert!(kernel.kernels.is_empty());
+    }
+
+    #[test]
+    fn kernel_with_process_but_no_init_is_not_used() {
+        let (mut kernel, tx) = kernel();
+        tx.send(compile(&format!(
+            "#[no_mangle] pub static ROCODER_KERNEL_ABI_VERSION: u32 = {};{}
+            #[no_mangle]
+            pub extern \"C\" fn process(
+                _: *mut u8,
+                _: &KernelContext,
+                bins: *mut [f32; 2],
+                len: usize,
+            ) -> i32 {{
+                let bins = unsafe {{ std::slice::from_raw_parts_mut(bins, len) }};
+                bins[0] = [9.0, 9.0];
+                0
+            }}",
+            KERNEL_ABI_VERSION, CONTEXT_DEF
+        )))
+        .unwrap();
+        let mut spectrum = vec![Complex32::new(0.5, 0.0); 9];
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum, vec![Complex32::new(0.5, 0.0); 9]);
+        assert!(kernel.kernels.is_empty());
+    }
+
+    #[test]
+    fn bypassed_kernel_leaves_spectrum_alone() {
+        let (mut kernel, tx) = kernel();
+        tx.send(compile(&counting_kernel(1, false, false))).unwrap();
+        let mut spectrum = vec![Complex32::new(0.0, 0.0); 9];
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum[0], Complex32::new(1.0, 1.0));
+
+        kernel.set_bypassed(true);
+        let mut spectrum = vec![Complex32::new(0.5, 0.0); 9];
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum, vec![Complex32::new(0.5, 0.0); 9]);
+
+        kernel.set_bypassed(false);
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum[0], Complex32::new(2.0, 1.0));
+        // windows are still counted while bypassed
+        assert_eq!(kernel.context.window, 3);
+    }
+
+    #[test_case(true, true, 5.0 ; "carries over with both hooks")]
+    #[test_case(true, false, 1.0 ; "starts afresh without load_state")]
+    #[test_case(false, true, 1.0 ; "starts afresh without save_state")]
+    fn state_persists_across_windows_and_reloads(
+        save_state: bool,
+        load_state: bool,
+        windows_after_reload: f32,
+    ) {
+        let (mut kernel, tx) = kernel();
+        let mut spectrum = vec![Complex32::new(0.0, 0.0); 9];
+        tx.send(compile(&counting_kernel(1, save_state, load_state)))
+            .unwrap();
+        for _ in 0..4 {
+            kernel.apply(&mut spectrum, Duration::ZERO);
+        }
+        assert_eq!(spectrum[0], Complex32::new(4.0, 1.0));
+
+        tx.send(compile(&counting_kernel(2, save_state, load_state)))
+            .unwrap();
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum[0], Complex32::new(windows_after_reload, 2.0));
+    }
+
+    #[test_case("capacity + 1" ; "that never fits")]
+    #[test_case("usize::MAX" ; "over the limit")]
+    fn state_is_not_carried_over_from_save_state_asking_for(len: &str) {
+        let (mut kernel, tx) = kernel();
+        let mut spectrum = vec![Complex32::new(0.0, 0.0); 9];
+        let mut src = counting_kernel(1, false, true);
+        src.push_str(&format!(
+            "
+            #[no_mangle]
+            pub extern \"C\" fn save_state(_: &Count, _: *mut u8, capacity: usize) -> usize {{
+                {}
+            }}
+            ",
+            len
+        ));
+        tx.send(compile(&src)).unwrap();
+        for _ in 0..4 {
+            kernel.apply(&mut spectrum, Duration::ZERO);
+        }
+
+        tx.send(compile(&counting_kernel(2, true, true))).unwrap();
+        kernel.apply(&mut spectrum, Duration::ZERO);
+        assert_eq!(spectrum[0], Complex32::new(1.0, 2.0));
+    }
+}
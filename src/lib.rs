@@ -5,21 +5,30 @@ mod test_utils;
 
 pub mod audio;
 pub mod audio_files;
+pub mod breakpoints;
 pub mod cpal_utils;
 pub mod crossfade;
 pub mod duration_parser;
 pub mod fft;
+pub mod formants;
 pub mod hotswapper;
+pub mod kernel;
 pub mod math;
 pub mod mixer;
+pub mod phase_vocoder;
+pub mod pitch_curve;
+pub mod pitch_parser;
 pub mod player_processor;
 pub mod power;
 pub mod recorder;
 pub mod recorder_processor;
 pub mod resampler;
 pub mod runtime_setup;
+pub mod segments;
 pub mod signal_flow;
 pub mod slices;
 pub mod stretcher;
 pub mod stretcher_processor;
+pub mod time_map;
+pub mod transients;
 pub mod windows;
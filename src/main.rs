@@ -1,17 +1,24 @@
 use rocoder::audio::{Audio, AudioBus, AudioSpec};
 use rocoder::audio_files::{AudioReader, AudioWriter, WavReader, WavWriter};
 use rocoder::duration_parser;
+use rocoder::fft::{ChannelPhases, KernelClock, PhaseRandomizer, ResynthMode};
+use rocoder::hotswapper;
+use rocoder::phase_vocoder::PhaseLocking;
+use rocoder::pitch_curve::PitchCurve;
+use rocoder::pitch_parser;
 use rocoder::player_processor::{AudioOutputProcessor, AudioOutputProcessorControlMessage};
 use rocoder::recorder;
+use rocoder::resampler::ResampleQuality;
 use rocoder::runtime_setup;
+use rocoder::segments;
 use rocoder::signal_flow::node::Node;
 use rocoder::stretcher::Stretcher;
 use rocoder::stretcher_processor::{StretcherProcessor, StretcherProcessorControlMessage};
-use rocoder::windows;
+use rocoder::time_map::TimeMap;
+use rocoder::windows::WindowFn;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use crossbeam_channel::unbounded;
-use ctrlc;
 
 use std::io;
 use std::path::PathBuf;
@@ -35,6 +42,20 @@ struct Opt {
     )]
     window_len: usize,
 
+    #[structopt(
+        long = "window-fn",
+        default_value = "hann",
+        help = "Window function: 'hann', 'blackman', 'blackman-harris', 'kaiser[:beta]', 'gaussian[:sigma]' or 'tukey[:alpha]'"
+    )]
+    window_fn: WindowFn,
+
+    #[structopt(
+        long = "overlap",
+        default_value = "2",
+        help = "How many processing windows overlap each output sample, e.g. 2, 4 or 8. Higher overlaps sound smoother in heavy stretches but take longer to compute"
+    )]
+    overlap: usize,
+
     #[structopt(
         short = "b", 
         long = "buffer", 
@@ -46,18 +67,116 @@ struct Opt {
     #[structopt(
         short = "f",
         long = "factor",
-        default_value = "1",
-        help = "Stretch factor; e.g. 5 to slow 5x and 0.2 to speed up 5x"
+        help = "Stretch factor; e.g. 5 to slow 5x and 0.2 to speed up 5x. Defaults to 1."
+    )]
+    factor: Option<f32>,
+
+    #[structopt(
+        long = "time-map",
+        conflicts_with = "factor",
+        help = "'input_time, factor' breakpoints to vary the stretch factor over the input, in a file or inline separated by semicolons. Replaces --factor"
+    )]
+    time_map: Option<TimeMap>,
+
+    #[structopt(
+        long = "target-duration",
+        conflicts_with_all = &["factor", "time-map", "target-bpm", "freeze-at"],
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Stretch the input to last exactly this long (hh:mm:ss.ss). Replaces --factor"
     )]
-    factor: f32,
+    target_duration: Option<Duration>,
+
+    #[structopt(
+        long = "target-bpm",
+        requires = "source-bpm",
+        conflicts_with_all = &["factor", "time-map"],
+        help = "Stretch the input from its --source-bpm tempo to this one. Replaces --factor"
+    )]
+    target_bpm: Option<f32>,
+
+    #[structopt(
+        long = "source-bpm",
+        requires = "target-bpm",
+        help = "The input's tempo, to stretch to --target-bpm"
+    )]
+    source_bpm: Option<f32>,
 
     #[structopt(
         short = "p",
         long = "pitch_multiple",
-        default_value = "1",
-        help = "A non-zero integer pitch multiplier."
+        help = "A non-zero integer pitch multiplier; shorthand for the matching --pitch ratio. Defaults to 1."
+    )]
+    pitch_multiple: Option<i8>,
+
+    #[structopt(
+        long = "pitch",
+        conflicts_with = "pitch-multiple",
+        allow_hyphen_values = true,
+        parse(try_from_str = pitch_parser::parse_pitch),
+        help = "Pitch shift in semitones (5st), cents (-120c) or as a frequency ratio (1.5)"
+    )]
+    pitch: Option<f32>,
+
+    #[structopt(
+        long = "pitch-curve",
+        conflicts_with_all = &["pitch", "pitch-multiple"],
+        allow_hyphen_values = true,
+        help = "'output_time, semitones' breakpoints to vary the pitch shift over the output, in a file or inline separated by semicolons"
     )]
-    pitch_multiple: i8,
+    pitch_curve: Option<PitchCurve>,
+
+    #[structopt(
+        long = "resample-quality",
+        default_value = "balanced",
+        help = "Quality of the band-limited resampling used for pitch shifts: 'fast', 'balanced' or 'best'. Higher qualities use longer filters which reject more aliasing but take longer to compute"
+    )]
+    resample_quality: ResampleQuality,
+
+    #[structopt(
+        long = "preserve-formants",
+        help = "Keep the spectral envelope in place through pitch shifts, so voices shift in pitch without sounding like chipmunks or giants"
+    )]
+    preserve_formants: bool,
+
+    #[structopt(
+        long = "resynth",
+        default_value = "random-phase",
+        help = "Resynthesis mode: 'random-phase' (Paulstretch-style smearing) or 'phase-vocoder' (phase-coherent, keeps speech and drums intelligible at small factors)"
+    )]
+    resynth_mode: ResynthMode,
+
+    #[structopt(
+        long = "channel-phases",
+        default_value = "linked",
+        help = "How random phases relate across channels: 'linked' shares random phase offsets between channels to keep the stereo image, 'wide' gives each channel unrelated phases for a diffuse, decorrelated image"
+    )]
+    channel_phases: ChannelPhases,
+
+    #[structopt(
+        long = "seed",
+        help = "Seed for the random phases, so renders with the same seed and options are identical. Defaults to a random seed"
+    )]
+    seed: Option<u64>,
+
+    #[structopt(
+        long = "phase-locking",
+        default_value = "identity",
+        help = "Phase locking used by phase-vocoder resynthesis: 'off', 'identity' or 'scaled'"
+    )]
+    phase_locking: PhaseLocking,
+
+    #[structopt(
+        long = "transients",
+        help = "Detect transients and resynthesize them with their original phases, keeping attacks sharp"
+    )]
+    transients: bool,
+
+    #[structopt(
+        long = "transient-sensitivity",
+        default_value = "0.5",
+        help = "How readily transients are detected when --transients is set, from 0 (never) to 1 (any rise in spectral flux)"
+    )]
+    transient_sensitivity: f32,
 
     #[structopt(
         short = "a",
@@ -67,6 +186,37 @@ struct Opt {
     )]
     amplitude: f32,
 
+    #[structopt(
+        long = "mid-side",
+        help = "Stretch stereo audio as mid and side channels, which can be given their own settings with the --side-* options, and decode the result back to left and right"
+    )]
+    mid_side: bool,
+
+    #[structopt(
+        long = "side-amplitude",
+        requires = "mid-side",
+        help = "Amplitude of the side channel with --mid-side; lower values narrow the stereo image. Defaults to --amplitude"
+    )]
+    side_amplitude: Option<f32>,
+
+    #[structopt(
+        long = "side-pitch",
+        requires = "mid-side",
+        allow_hyphen_values = true,
+        parse(try_from_str = pitch_parser::parse_pitch),
+        help = "Pitch shift of the side channel with --mid-side, in the same format as --pitch. Defaults to the mid channel's pitch"
+    )]
+    side_pitch: Option<f32>,
+
+    #[structopt(
+        long = "side-freq-kernel",
+        requires = "mid-side",
+        number_of_values = 1,
+        parse(from_os_str),
+        help = "Path to a rust frequency kernel file or cargo project for the side channel with --mid-side, and can be repeated like --freq-kernel. Defaults to --freq-kernel"
+    )]
+    side_freq_kernel: Vec<PathBuf>,
+
     #[structopt(
         short = "i",
         long = "input",
@@ -83,10 +233,11 @@ struct Opt {
 
     #[structopt(
         long = "freq-kernel",
-        help = "Path to a rust frequency kernel file",
+        number_of_values = 1,
+        help = "Path to a rust frequency kernel file, or a cargo project directory building one. Repeat to chain several kernels, which are run in the order given",
         parse(from_os_str)
     )]
-    freq_kernel: Option<PathBuf>,
+    freq_kernel: Vec<PathBuf>,
 
     #[structopt(
         short = "x",
@@ -107,11 +258,18 @@ struct Opt {
     #[structopt(
         short = "d",
         long = "duration",
-        help = "Duration to use from input audio, starting at start time if given (hh:mm:ss.ss)",
+        help = "Duration to use from input audio, starting at start time if given, or with --freeze-at how long to hold the freeze (hh:mm:ss.ss)",
 	parse(try_from_str = duration_parser::parse_duration)
     )]
     duration: Option<Duration>,
 
+    #[structopt(
+        long = "freeze-at",
+        help = "Freeze the sound at this time in the input (hh:mm:ss.ss), sustaining it for --duration, or until Enter is pressed when playing live",
+        parse(try_from_str = duration_parser::parse_duration)
+    )]
+    freeze_at: Option<Duration>,
+
     #[structopt(
         short = "o",
         long = "output",
@@ -125,34 +283,195 @@ fn main() -> Result<()> {
     runtime_setup::setup_logging();
     let opt = Opt::from_args();
 
-    let audio = load_audio(&opt);
+    if opt.overlap < 2 || !opt.window_len.is_multiple_of(opt.overlap) {
+        bail!("--overlap must be at least 2 and divide the window size evenly");
+    }
+    if opt.pitch_multiple == Some(0) {
+        bail!("--pitch_multiple must be non-zero");
+    }
+    if let Some(factor) = opt.factor {
+        if !factor.is_finite() || factor <= 0.0 {
+            bail!("--factor must be a positive number");
+        }
+    }
+    for bpm in [opt.target_bpm, opt.source_bpm].into_iter().flatten() {
+        if !bpm.is_finite() || bpm <= 0.0 {
+            bail!("--target-bpm and --source-bpm must be positive numbers");
+        }
+    }
+    for kernel in opt.freq_kernel.iter().chain(&opt.side_freq_kernel) {
+        hotswapper::check_kernel_source(kernel)?;
+    }
+    if opt.freeze_at.is_some() && opt.duration.is_none() && opt.output.is_some() {
+        bail!("--freeze-at needs a --duration to hold the freeze for when writing to a file");
+    }
+    let mut audio = load_audio(&opt);
+    if opt.mid_side {
+        if audio.data.len() != 2 {
+            bail!("--mid-side requires stereo input");
+        }
+        audio.encode_mid_side_in_place();
+    }
     let total_samples_len = audio.data[0].len();
     let spec = audio.spec;
-    let window = windows::hanning(opt.window_len);
+    if let Some(freeze_at) = opt.freeze_at {
+        if freeze_at.as_secs_f64() * spec.sample_rate as f64 >= total_samples_len as f64 {
+            bail!("--freeze-at is past the end of the input");
+        }
+    }
+    let window = opt.window_fn.generate(opt.window_len);
+    let resynth_mode = match opt.resynth_mode {
+        ResynthMode::PhaseVocoder(_) => ResynthMode::PhaseVocoder(opt.phase_locking),
+        mode => mode,
+    };
+    let pitch_curve = match (&opt.pitch_curve, opt.pitch, opt.pitch_multiple) {
+        (Some(pitch_curve), _, _) => pitch_curve.clone(),
+        (None, Some(pitch), _) => PitchCurve::constant(pitch),
+        (None, None, Some(pitch_multiple)) => {
+            PitchCurve::constant(pitch_parser::pitch_multiple_to_ratio(pitch_multiple))
+        }
+        (None, None, None) => PitchCurve::constant(1.0),
+    };
+    let target_len = opt
+        .target_duration
+        .map(|duration| (duration.as_secs_f64() * spec.sample_rate as f64).round() as usize);
+    if target_len.is_some() && total_samples_len == 0 {
+        bail!("--target-duration needs some input to stretch");
+    }
+    let time_map = match (&opt.time_map, target_len, opt.target_bpm, opt.source_bpm) {
+        (Some(time_map), _, _, _) => time_map.clone(),
+        (None, Some(target_len), _, _) => TimeMap::fitting(total_samples_len, target_len),
+        (None, None, Some(target_bpm), Some(source_bpm)) => {
+            TimeMap::constant(source_bpm / target_bpm)
+        }
+        _ => TimeMap::constant(opt.factor.unwrap_or(1.0)),
+    };
+    let transient_sensitivity = if opt.transients {
+        Some(opt.transient_sensitivity)
+    } else {
+        None
+    };
 
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    let phase_randomizers =
+        PhaseRandomizer::for_channels(audio.data.len(), opt.channel_phases, seed);
+    // live kernels follow real time, while renders to files need to be reproducible
+    let kernel_clock = if opt.output.is_some() {
+        KernelClock::AudioTime
+    } else {
+        KernelClock::WallClock
+    };
+    // renders last exactly as long as the stretched input, or the target
+    // duration it's stretched to, and a freeze is held after the input
+    // stretched up to it. Live playback just runs until the input does.
+    let output_limit = match (opt.freeze_at, target_len) {
+        (Some(freeze_at), _) => opt.duration.map(|duration| {
+            let freeze_at_samples = (freeze_at.as_secs_f64() * spec.sample_rate as f64) as usize;
+            time_map.stretched_len(freeze_at_samples, spec.sample_rate)
+                + (duration.as_secs_f64() * spec.sample_rate as f64) as usize
+        }),
+        (None, Some(target_len)) => Some(target_len),
+        (None, None) if opt.output.is_some() => {
+            Some(time_map.stretched_len(total_samples_len, spec.sample_rate))
+        }
+        (None, None) => None,
+    };
+    // live playback also fades out at the end of the stretched input, which
+    // is pushed back whenever the sound is frozen. A freeze that's held
+    // without a duration has no end.
+    let expected_total_samples = match (output_limit, opt.freeze_at) {
+        (Some(output_limit), _) => Some(output_limit),
+        (None, Some(_)) => None,
+        (None, None) => Some(time_map.stretched_len(total_samples_len, spec.sample_rate)),
+    };
+    // without anything carried from one frame to the next, offline renders can be split up
+    let renders_segments = opt.output.is_some()
+        && resynth_mode == ResynthMode::RandomPhase
+        && opt.pitch_curve.is_none()
+        && opt.freeze_at.is_none()
+        && opt.freq_kernel.is_empty()
+        && opt.side_freq_kernel.is_empty();
+    // channels are rendered at the same time, so they share the cores
+    let segment_threads = segments::threads_per_channel(audio.data.len());
+    if renders_segments {
+        info!("rendering in parallel segments");
+    }
     let stretchers = audio
         .data
         .into_iter()
-        .map(|channel| {
-            let (stretcher_in_tx, stretcher_in_rx) = unbounded();
-            let stretcher = Stretcher::new(
-                spec,
-                stretcher_in_rx,
-                opt.factor,
-                opt.amplitude,
-                opt.pitch_multiple,
-                window.clone(),
-                opt.buffer_dur,
-                opt.freq_kernel.clone(),
-            );
-            if stretcher_in_tx.send(channel).is_err() {
-                warn!("failed to send channel data");
+        .zip(phase_randomizers)
+        .enumerate()
+        .map(|(i, (channel, phase_randomizer))| {
+            let is_side = opt.mid_side && i == 1;
+            let (amplitude, pitch_curve, freq_kernels) = if is_side {
+                (
+                    opt.side_amplitude.unwrap_or(opt.amplitude),
+                    opt.side_pitch
+                        .map(PitchCurve::constant)
+                        .unwrap_or_else(|| pitch_curve.clone()),
+                    if opt.side_freq_kernel.is_empty() {
+                        opt.freq_kernel.clone()
+                    } else {
+                        opt.side_freq_kernel.clone()
+                    },
+                )
+            } else {
+                (opt.amplitude, pitch_curve.clone(), opt.freq_kernel.clone())
+            };
+            let make_stretcher = {
+                let time_map = time_map.clone();
+                let window = window.clone();
+                let (resample_quality, overlap) = (opt.resample_quality, opt.overlap);
+                let (preserve_formants, buffer_dur) = (opt.preserve_formants, opt.buffer_dur);
+                move |input_rx| {
+                    Stretcher::new(
+                        spec,
+                        input_rx,
+                        time_map.clone(),
+                        amplitude,
+                        pitch_curve.clone(),
+                        resample_quality,
+                        window.clone(),
+                        overlap,
+                        resynth_mode,
+                        transient_sensitivity,
+                        phase_randomizer.clone(),
+                        preserve_formants,
+                        buffer_dur,
+                        i,
+                        kernel_clock,
+                        freq_kernels.clone(),
+                    )
+                }
+            };
+            let mut stretcher = if renders_segments {
+                // the stretcher and its prerendering share the one copy of the input
+                let input: Arc<[f32]> = channel.into();
+                let mut stretcher = make_stretcher(segments::feed(input.clone()));
+                stretcher.use_prerendered(segments::prerender(
+                    input,
+                    segment_threads,
+                    make_stretcher,
+                ));
+                stretcher
+            } else {
+                let (stretcher_in_tx, stretcher_in_rx) = unbounded();
+                if stretcher_in_tx.send(channel).is_err() {
+                    warn!("failed to send channel data");
+                }
+                make_stretcher(stretcher_in_rx)
+            };
+            if let Some(freeze_at) = opt.freeze_at {
+                stretcher.freeze_at(freeze_at);
+            }
+            if let Some(output_limit) = output_limit {
+                stretcher.limit_output(output_limit);
             }
             stretcher
         })
         .collect();
-    let expected_total_samples = Some((total_samples_len as f32 * opt.factor) as usize);
-    let (stretcher_processor, bus) = StretcherProcessor::new(stretchers, expected_total_samples);
+    let (stretcher_processor, bus) =
+        StretcherProcessor::new(stretchers, expected_total_samples, opt.mid_side);
     let stretcher_node = Node::new(stretcher_processor);
 
     handle_result(&opt, bus, stretcher_node)?;
@@ -176,8 +495,14 @@ fn load_audio(opt: &Opt) -> Audio {
         }),
     };
 
-    if opt.start.is_some() || opt.duration.is_some() {
-        audio.clip_in_place(opt.start, opt.duration);
+    // with a freeze, the duration is how long the freeze lasts instead
+    let duration = if opt.freeze_at.is_some() {
+        None
+    } else {
+        opt.duration
+    };
+    if opt.start.is_some() || duration.is_some() {
+        audio.clip_in_place(opt.start, duration);
     }
 
     if opt.rotate_channels {
@@ -203,13 +528,97 @@ fn handle_result(
             writer.finalize().unwrap();
         }
         None => {
+            let stretcher_node = Arc::new(stretcher_node);
+            if !reads_stdin(opt) {
+                let kernel_stages = opt.freq_kernel.len().max(opt.side_freq_kernel.len());
+                control_from_stdin(
+                    Arc::clone(&stretcher_node),
+                    opt.freeze_at.is_some(),
+                    kernel_stages,
+                );
+            }
             play(audio_bus, Some(opt.fade));
+            return Ok(());
         }
     }
     stretcher_node.join();
     Ok(())
 }
 
+fn reads_stdin(opt: &Opt) -> bool {
+    opt.input.as_ref().and_then(|path| path.to_str()) == Some("-")
+}
+
+/// Freeze and release the sound each time Enter is pressed while playing
+/// live, or bypass a kernel and bring it back when its number is entered
+fn control_from_stdin(
+    node: Arc<Node<StretcherProcessor, StretcherProcessorControlMessage>>,
+    initially_frozen: bool,
+    kernel_stages: usize,
+) {
+    println!("Press Enter to freeze and release the sound");
+    if kernel_stages > 0 {
+        println!(
+            "Enter a kernel's number from 1 to {} to bypass it or bring it back",
+            kernel_stages
+        );
+    }
+    thread::spawn(move || {
+        let mut frozen = initially_frozen;
+        let mut bypassed = vec![false; kernel_stages];
+        let mut input = String::new();
+        while let Ok(1..) = io::stdin().read_line(&mut input) {
+            let command = input.trim().to_string();
+            input.clear();
+            let (message, status) = match command.as_str() {
+                "" => {
+                    frozen = !frozen;
+                    if frozen {
+                        (
+                            StretcherProcessorControlMessage::Freeze,
+                            "Frozen".to_string(),
+                        )
+                    } else {
+                        (
+                            StretcherProcessorControlMessage::Release,
+                            "Released".to_string(),
+                        )
+                    }
+                }
+                command => match command.parse::<usize>() {
+                    Ok(number @ 1..) if number <= kernel_stages => {
+                        let stage = number - 1;
+                        bypassed[stage] = !bypassed[stage];
+                        (
+                            StretcherProcessorControlMessage::BypassKernel {
+                                stage,
+                                bypassed: bypassed[stage],
+                            },
+                            format!(
+                                "Kernel {} {}",
+                                number,
+                                if bypassed[stage] {
+                                    "bypassed"
+                                } else {
+                                    "back on"
+                                }
+                            ),
+                        )
+                    }
+                    _ => {
+                        println!("Unknown command '{}'", command);
+                        continue;
+                    }
+                },
+            };
+            if node.send_control_message(message).is_err() {
+                break;
+            }
+            println!("{}", status);
+        }
+    });
+}
+
 const PLAY_POLL: Duration = Duration::from_millis(500);
 
 fn play(bus: AudioBus, fade: Option<Duration>) {
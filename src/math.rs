@@ -60,6 +60,7 @@ mod test_clamp {
 }
 
 #[cfg(test)]
+#[allow(clippy::excessive_precision)]
 mod test_lerp {
     use super::*;
     use crate::test_utils::*;
@@ -44,6 +44,9 @@ struct Layer {
     buffer_pos: usize,
     shutdown_when_finished: bool,
     last_status_report_instant: Instant,
+    fade_out_dur: Option<Duration>,
+    /// where the scheduled fade-out starts, and the expected length it was scheduled for
+    fade_out: Option<(usize, usize)>,
 }
 
 impl Layer {
@@ -56,10 +59,13 @@ impl Layer {
             total_samples_played: 0,
             buffer_pos: 0,
             last_status_report_instant: Instant::now(),
+            fade_out_dur: None,
+            fade_out: None,
         }
     }
 
     fn load_next_chunk(&mut self) -> Result<()> {
+        self.schedule_fade_out();
         self.prune_keyframes();
         self.log_status();
         let mut chunk = self.bus.collect_chunk()?;
@@ -82,7 +88,7 @@ impl Layer {
         let played_dur = Duration::from_secs_f32(
             self.total_samples_played as f32 / self.bus.spec.sample_rate as f32,
         );
-        match self.bus.expected_total_samples {
+        match self.bus.expected_total_samples.get() {
             Some(expected_total_samples) => {
                 let total_dur = Duration::from_secs_f32(
                     expected_total_samples as f32 / self.bus.spec.sample_rate as f32,
@@ -107,13 +113,12 @@ impl Layer {
     #[inline]
     fn prune_keyframes(&mut self) {
         loop {
-            if self.amp_keyframes.len() > 1 {
-                if self.amp_keyframes[self.amp_keyframes.len() - 2].sample_pos
+            if self.amp_keyframes.len() > 1
+                && self.amp_keyframes[self.amp_keyframes.len() - 2].sample_pos
                     < self.total_samples_played
-                {
-                    self.amp_keyframes.pop();
-                    continue;
-                }
+            {
+                self.amp_keyframes.pop();
+                continue;
             }
             break;
         }
@@ -177,15 +182,45 @@ impl Layer {
 
     /// only fades out if both `fade_out_dur` and `self.bus.expected_total_samples` are present
     pub fn fade_in_out(&mut self, fade_in_dur: Option<Duration>, fade_out_dur: Option<Duration>) {
-        if fade_in_dur.is_some() {
-            self.fade(Duration::from_secs(0), 0.0, fade_in_dur.unwrap(), 1.0);
+        if let Some(fade_in_dur) = fade_in_dur {
+            self.fade(Duration::from_secs(0), 0.0, fade_in_dur, 1.0);
+        }
+        self.fade_out_dur = fade_out_dur;
+        self.schedule_fade_out();
+    }
+
+    /// Fade out towards the bus's expected end, moving the fade-out if that
+    /// end has moved since it was scheduled
+    fn schedule_fade_out(&mut self) {
+        let fade_out_dur = match self.fade_out_dur {
+            Some(fade_out_dur) => fade_out_dur,
+            None => return,
+        };
+        let expected_total_samples = self.bus.expected_total_samples.get();
+        if self.fade_out.map(|(_, scheduled_for)| scheduled_for) == expected_total_samples {
+            return;
         }
-        if fade_out_dur.is_some() && self.bus.expected_total_samples.is_some() {
+        if let Some((fade_start, _)) = self.fade_out.take() {
+            let current_amp = self.current_amp();
+            self.amp_keyframes.retain(|k| k.sample_pos < fade_start);
+            if self.total_samples_played > fade_start {
+                // hold a fade-out that's under way where it got to
+                self.amp_keyframes.push(Keyframe {
+                    sample_pos: self.total_samples_played,
+                    val: current_amp,
+                });
+                self.sort_keyframes();
+            }
+        }
+        if let Some(expected_total_samples) = expected_total_samples {
             let total_dur = Duration::from_secs_f32(
-                self.bus.expected_total_samples.unwrap() as f32 / self.bus.spec.sample_rate as f32,
+                expected_total_samples as f32 / self.bus.spec.sample_rate as f32,
             );
-            let fade_start = total_dur - fade_out_dur.unwrap();
-            self.fade(fade_start, 1.0, fade_out_dur.unwrap(), 0.0);
+            // big speed-ups can finish sooner than the fade would take
+            let fade_out_dur = fade_out_dur.min(total_dur);
+            let fade_start = total_dur - fade_out_dur;
+            self.fade(fade_start, 1.0, fade_out_dur, 0.0);
+            self.fade_out = Some((self.dur_to_sample(fade_start), expected_total_samples));
         }
     }
 }
@@ -257,7 +292,10 @@ impl Mixer {
 
     pub fn fade_from_now(&mut self, id: u32, to: f32, dur: Duration) -> Result<()> {
         match self.layers.get_mut(&id) {
-            Some(layer) => Ok(layer.fade_from_now(to, dur)),
+            Some(layer) => {
+                layer.fade_from_now(to, dur);
+                Ok(())
+            }
             None => bail!("Layer not found"),
         }
     }
@@ -271,7 +309,10 @@ impl Mixer {
         end_val: f32,
     ) -> Result<()> {
         match self.layers.get_mut(&id) {
-            Some(layer) => Ok(layer.fade(start, start_val, dur, end_val)),
+            Some(layer) => {
+                layer.fade(start, start_val, dur, end_val);
+                Ok(())
+            }
             None => bail!("Layer not found"),
         }
     }
@@ -284,7 +325,10 @@ impl Mixer {
         fade_out_dur: Option<Duration>,
     ) -> Result<()> {
         match self.layers.get_mut(&id) {
-            Some(layer) => Ok(layer.fade_in_out(fade_in_dur, fade_out_dur)),
+            Some(layer) => {
+                layer.fade_in_out(fade_in_dur, fade_out_dur);
+                Ok(())
+            }
             None => bail!("Layer not found"),
         }
     }
@@ -293,6 +337,7 @@ impl Mixer {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::audio::ExpectedLength;
     use crate::test_utils::*;
     use crossbeam_channel::unbounded;
 
@@ -351,6 +396,41 @@ mod test {
         assert_almost_eq(layer.amp_keyframes[1].val, 0.5);
     }
 
+    #[test]
+    fn fade_out_longer_than_layer_starts_at_beginning() {
+        let mut layer = basic_layer();
+        layer.bus.expected_total_samples.set(Some(441));
+        layer.fade_in_out(None, Some(Duration::from_secs(2)));
+        // remember - amp_keyframes is kept in reverse order
+        assert_eq!(layer.amp_keyframes[1].sample_pos, 0);
+        assert_eq!(layer.amp_keyframes[0].sample_pos, 441);
+        assert_almost_eq(layer.amp_keyframes[0].val, 0.0);
+    }
+
+    #[test]
+    fn fade_out_follows_expected_length() {
+        let mut layer = basic_layer();
+        layer.bus.expected_total_samples.set(Some(44100 * 10));
+        layer.fade_in_out(None, Some(Duration::from_secs(2)));
+        assert_eq!(layer.amp_keyframes.len(), 2);
+
+        // the end is unknown while frozen, so a fade-out under way is held
+        layer.total_samples_played = 44100 * 9;
+        let amp = layer.current_amp();
+        layer.bus.expected_total_samples.set(None);
+        layer.schedule_fade_out();
+        assert_eq!(layer.amp_keyframes.len(), 1);
+        assert_almost_eq(layer.current_amp(), amp);
+
+        // and moved to the new end once released
+        layer.bus.expected_total_samples.set(Some(44100 * 20));
+        layer.schedule_fade_out();
+        assert_eq!(layer.amp_keyframes.len(), 3);
+        assert_eq!(layer.amp_keyframes[0].sample_pos, 44100 * 20);
+        assert_eq!(layer.amp_keyframes[1].sample_pos, 44100 * 18);
+        assert_almost_eq(layer.amp_keyframes[0].val, 0.0);
+    }
+
     fn basic_layer() -> Layer {
         let (_, rx) = unbounded();
         let spec = AudioSpec {
@@ -360,7 +440,7 @@ mod test {
         let bus = AudioBus {
             spec,
             channels: vec![rx],
-            expected_total_samples: None,
+            expected_total_samples: ExpectedLength::new(None),
         };
         Layer::new(bus, false)
     }
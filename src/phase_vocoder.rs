@@ -0,0 +1,281 @@
+use anyhow::{bail, Error, Result};
+use rustfft::num_complex::Complex32;
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+const TWO_PI: f32 = PI * 2.0;
+
+/// How non-peak bins are tied to nearby spectral peaks during phase propagation
+///
+/// See Laroche & Dolson, "Improved phase vocoder time-scale modification of
+/// audio" (1999).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PhaseLocking {
+    /// Propagate every bin independently at its own instantaneous frequency
+    Off,
+    /// Keep each bin's analysis phase offset from the peak whose region it lies in
+    #[default]
+    Identity,
+    /// Like `Identity`, but scale the phase offsets along with the stretch
+    Scaled,
+}
+
+impl FromStr for PhaseLocking {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" | "none" => Ok(PhaseLocking::Off),
+            "identity" => Ok(PhaseLocking::Identity),
+            "scaled" => Ok(PhaseLocking::Scaled),
+            _ => bail!(
+                "Invalid phase locking '{}'; expected 'off', 'identity' or 'scaled'",
+                s
+            ),
+        }
+    }
+}
+
+/// Phase propagation state for phase-coherent resynthesis of one channel
+///
+/// Each bin's instantaneous frequency is estimated from the phase difference
+/// between consecutive analysis windows, and its output phase is advanced
+/// at that frequency over the synthesis hop. Spectra are the non-negative
+/// frequency half of a real signal's spectrum.
+pub struct PhaseVocoder {
+    window_len: usize,
+    locking: PhaseLocking,
+    magnitudes: Vec<f32>,
+    analysis_phases: Vec<f32>,
+    last_analysis_phases: Vec<f32>,
+    synth_phases: Vec<f32>,
+    peaks: Vec<usize>,
+    primed: bool,
+}
+
+impl PhaseVocoder {
+    pub fn new(window_len: usize, locking: PhaseLocking) -> PhaseVocoder {
+        let bins = window_len / 2 + 1;
+        PhaseVocoder {
+            window_len,
+            locking,
+            magnitudes: vec![0.0; bins],
+            analysis_phases: vec![0.0; bins],
+            last_analysis_phases: vec![0.0; bins],
+            synth_phases: vec![0.0; bins],
+            peaks: vec![],
+            primed: false,
+        }
+    }
+
+    /// Restart propagation from the next window's analysis phases
+    pub fn reset(&mut self) {
+        self.primed = false;
+    }
+
+    /// Replace the phases of `spectrum` in place with propagated ones.
+    ///
+    /// `analysis_hop` is the number of input samples between this window and
+    /// the last one, `synthesis_hop` the number of output samples between them.
+    pub fn propagate(
+        &mut self,
+        spectrum: &mut [Complex32],
+        analysis_hop: usize,
+        synthesis_hop: usize,
+    ) {
+        debug_assert!(spectrum.len() == self.magnitudes.len());
+        let bins = self.magnitudes.len();
+        for (k, bin) in spectrum.iter().enumerate() {
+            let (magnitude, phase) = bin.to_polar();
+            self.magnitudes[k] = magnitude;
+            self.analysis_phases[k] = phase;
+        }
+        if !self.primed {
+            self.synth_phases.copy_from_slice(&self.analysis_phases);
+            self.primed = true;
+        } else if self.locking == PhaseLocking::Off {
+            for k in 0..bins {
+                self.advance_bin(k, analysis_hop, synthesis_hop);
+            }
+        } else {
+            self.propagate_locked(analysis_hop, synthesis_hop);
+        }
+        self.last_analysis_phases
+            .copy_from_slice(&self.analysis_phases);
+        for (k, bin) in spectrum.iter_mut().enumerate() {
+            *bin = Complex32::from_polar(self.magnitudes[k], self.synth_phases[k]);
+        }
+    }
+
+    /// Propagate spectral peaks on their own, and lock every other bin to
+    /// the peak whose region of influence it lies in.
+    fn propagate_locked(&mut self, analysis_hop: usize, synthesis_hop: usize) {
+        find_peaks(&self.magnitudes, &mut self.peaks);
+        if self.peaks.is_empty() {
+            for k in 0..self.magnitudes.len() {
+                self.advance_bin(k, analysis_hop, synthesis_hop);
+            }
+            return;
+        }
+        let phase_offset_scale = match self.locking {
+            PhaseLocking::Scaled => {
+                2.0 / 3.0 + (synthesis_hop as f32 / analysis_hop.max(1) as f32) / 3.0
+            }
+            _ => 1.0,
+        };
+        let mut region_start = 0;
+        for i in 0..self.peaks.len() {
+            let peak = self.peaks[i];
+            self.advance_bin(peak, analysis_hop, synthesis_hop);
+            // regions of influence are split at the quietest bin between adjacent peaks
+            let region_end = match self.peaks.get(i + 1) {
+                Some(&next_peak) => {
+                    (peak + 1..next_peak)
+                        .min_by(|a, b| self.magnitudes[*a].total_cmp(&self.magnitudes[*b]))
+                        .unwrap_or(peak)
+                        + 1
+                }
+                None => self.magnitudes.len(),
+            };
+            for k in region_start..region_end {
+                if k != peak {
+                    // Offsets are scaled as if the window were centered on the time origin,
+                    // where neighboring bins of a sinusoid are in phase rather than PI apart.
+                    let centering = PI * (k as f32 - peak as f32);
+                    self.synth_phases[k] = self.synth_phases[peak]
+                        + phase_offset_scale
+                            * wrap_phase(
+                                self.analysis_phases[k] - self.analysis_phases[peak] + centering,
+                            )
+                        - centering;
+                }
+            }
+            region_start = region_end;
+        }
+    }
+
+    /// Advance bin `k`'s synthesis phase at its estimated instantaneous frequency
+    #[inline]
+    fn advance_bin(&mut self, k: usize, analysis_hop: usize, synthesis_hop: usize) {
+        let hop_ratio = synthesis_hop as f32 / analysis_hop.max(1) as f32;
+        let expected_advance = self.bin_advance(k, analysis_hop);
+        let deviation =
+            wrap_phase(self.analysis_phases[k] - self.last_analysis_phases[k] - expected_advance);
+        self.synth_phases[k] =
+            (self.synth_phases[k] + self.bin_advance(k, synthesis_hop) + deviation * hop_ratio)
+                .rem_euclid(TWO_PI);
+    }
+
+    /// The phase a sinusoid centered on bin `k` advances by over `hop` samples
+    ///
+    /// Computed with integer arithmetic so large bins and hops don't lose precision.
+    #[inline]
+    fn bin_advance(&self, k: usize, hop: usize) -> f32 {
+        TWO_PI * ((k * hop) % self.window_len) as f32 / self.window_len as f32
+    }
+}
+
+/// Find bins louder than their two neighbors on either side
+fn find_peaks(magnitudes: &[f32], peaks: &mut Vec<usize>) {
+    peaks.clear();
+    for k in 0..magnitudes.len() {
+        let neighbors = k.saturating_sub(2)..(k + 3).min(magnitudes.len());
+        if magnitudes[k] > 0.0
+            && neighbors
+                .filter(|n| *n != k)
+                .all(|n| magnitudes[n] < magnitudes[k])
+        {
+            peaks.push(k);
+        }
+    }
+}
+
+/// Wrap a phase into the range `-PI..=PI`
+#[inline]
+pub fn wrap_phase(phase: f32) -> f32 {
+    phase - TWO_PI * (phase / TWO_PI).round()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn wrap_phase_result() {
+        assert_almost_eq(wrap_phase(0.5), 0.5);
+        assert_almost_eq(wrap_phase(TWO_PI + 0.5), 0.5);
+        assert_almost_eq(wrap_phase(-TWO_PI * 3.0 - 0.5), -0.5);
+    }
+
+    #[test]
+    fn find_peaks_result() {
+        let mut peaks = vec![];
+        find_peaks(&[0.0, 1.0, 3.0, 1.0, 0.5, 0.4, 0.6, 2.0, 0.1], &mut peaks);
+        assert_eq!(peaks, vec![2, 7]);
+    }
+
+    #[test]
+    fn first_window_keeps_analysis_phases() {
+        let mut pv = PhaseVocoder::new(8, PhaseLocking::Off);
+        let mut spectrum = vec![Complex32::from_polar(1.0, 0.3); 5];
+        pv.propagate(&mut spectrum, 1, 2);
+        for bin in &spectrum {
+            assert_almost_eq(bin.arg(), 0.3);
+            assert_almost_eq(bin.norm(), 1.0);
+        }
+    }
+
+    #[test]
+    fn phase_deviation_scales_with_hop_ratio() {
+        let window_len = 8;
+        let mut pv = PhaseVocoder::new(window_len, PhaseLocking::Off);
+        let mut first = vec![Complex32::from_polar(1.0, 0.0); window_len / 2 + 1];
+        pv.propagate(&mut first, 2, 4);
+        // bin 1 advances by its expected 2 * TWO_PI / 8 plus a deviation of 0.1
+        let mut second = vec![Complex32::from_polar(1.0, 0.0); window_len / 2 + 1];
+        second[1] = Complex32::from_polar(1.0, TWO_PI / 4.0 + 0.1);
+        pv.propagate(&mut second, 2, 4);
+        assert_almost_eq(second[1].arg(), TWO_PI / 2.0 + 0.2 - TWO_PI);
+        assert_almost_eq(second[1].norm(), 1.0);
+    }
+
+    #[test]
+    fn identity_locking_keeps_phase_offsets_from_peak() {
+        let offsets = locked_phase_offsets_from_peak(PhaseLocking::Identity);
+        assert_almost_eq(offsets[0], wrap_phase(PI + 0.2));
+        assert_almost_eq(offsets[1], wrap_phase(PI - 0.3));
+    }
+
+    #[test]
+    fn scaled_locking_scales_centered_phase_offsets_from_peak() {
+        // hop ratio of 4 gives a scale of 2/3 + 4/3 = 2
+        let offsets = locked_phase_offsets_from_peak(PhaseLocking::Scaled);
+        assert_almost_eq(offsets[0], wrap_phase(PI + 0.4));
+        assert_almost_eq(offsets[1], wrap_phase(PI - 0.6));
+    }
+
+    /// Propagate two windows with a single peak at bin 4 and return the
+    /// synthesized phase offsets of bins 3 and 5 from it.
+    ///
+    /// As with a real windowed sinusoid, the bins next to the peak are about
+    /// PI out of phase with it.
+    fn locked_phase_offsets_from_peak(locking: PhaseLocking) -> Vec<f32> {
+        let window_len = 16;
+        let magnitudes = [0.1, 0.1, 0.2, 0.5, 1.0, 0.5, 0.2, 0.1, 0.1];
+        let phases = [0.0, 0.0, 0.0, PI + 0.7, 0.5, PI + 0.2, 0.0, 0.0, 0.0];
+        let mut pv = PhaseVocoder::new(window_len, locking);
+        for _ in 0..2 {
+            let mut spectrum: Vec<Complex32> = magnitudes
+                .iter()
+                .zip(phases)
+                .map(|(magnitude, phase)| Complex32::from_polar(*magnitude, phase))
+                .collect();
+            pv.propagate(&mut spectrum, 1, 4);
+        }
+        vec![
+            wrap_phase(pv.synth_phases[3] - pv.synth_phases[4]),
+            wrap_phase(pv.synth_phases[5] - pv.synth_phases[4]),
+        ]
+    }
+}
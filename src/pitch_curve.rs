@@ -0,0 +1,58 @@
+use crate::breakpoints::Breakpoints;
+use crate::pitch_parser;
+use anyhow::{Error, Result};
+use std::str::FromStr;
+
+/// A pitch shift that varies over the course of the output
+///
+/// Defined by breakpoints of output time and pitch interval in semitones,
+/// given either in a file or inline like `0, 0; 20:00, -12`. Intervals are
+/// interpolated in semitones, so glides sound even across their range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PitchCurve {
+    semitones: Breakpoints,
+}
+
+impl PitchCurve {
+    /// A fixed pitch shift by the frequency ratio `ratio`
+    pub fn constant(ratio: f32) -> PitchCurve {
+        PitchCurve {
+            semitones: Breakpoints::constant(12.0 * ratio.log2()),
+        }
+    }
+
+    /// The frequency ratio at `time` seconds into the output
+    pub fn ratio_at(&self, time: f32) -> f32 {
+        2f32.powf(self.semitones.value_at(time) / 12.0)
+    }
+}
+
+impl FromStr for PitchCurve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(PitchCurve {
+            semitones: Breakpoints::from_arg(s, pitch_parser::parse_semitones)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn constant_curve_keeps_ratio() {
+        assert_almost_eq(PitchCurve::constant(1.0).ratio_at(3.0), 1.0);
+        assert_almost_eq(PitchCurve::constant(1.5).ratio_at(3.0), 1.5);
+    }
+
+    #[test]
+    fn ratio_at_interpolates_in_semitones() {
+        let curve: PitchCurve = "0, 0; 10, -12st; 20, 1200c".parse().unwrap();
+        assert_almost_eq(curve.ratio_at(5.0), 2f32.powf(-0.5));
+        assert_almost_eq(curve.ratio_at(10.0), 0.5);
+        assert_almost_eq(curve.ratio_at(30.0), 2.0);
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{bail, Result};
+
+/// Parse a pitch shift into a frequency ratio.
+///
+/// Accepts semitones (`5st`, `-3st`), cents (`-120c`), or a bare ratio (`1.5`).
+pub fn parse_pitch(pitch_str: &str) -> Result<f32> {
+    let ratio = if let Some(semitones) = pitch_str.strip_suffix("st") {
+        2f32.powf(semitones.trim().parse::<f32>()? / 12.0)
+    } else if let Some(cents) = pitch_str.strip_suffix('c') {
+        2f32.powf(cents.trim().parse::<f32>()? / 1200.0)
+    } else {
+        pitch_str.parse::<f32>()?
+    };
+    if !ratio.is_finite() || ratio <= 0.0 {
+        bail!("Pitch ratio must be a positive number");
+    }
+    Ok(ratio)
+}
+
+/// Parse a pitch interval into semitones.
+///
+/// Accepts semitones with or without a suffix (`5`, `-3st`), or cents (`-120c`).
+pub fn parse_semitones(interval_str: &str) -> Result<f32> {
+    let semitones = if let Some(cents) = interval_str.strip_suffix('c') {
+        cents.trim().parse::<f32>()? / 100.0
+    } else {
+        let semitones = interval_str.strip_suffix("st").unwrap_or(interval_str);
+        semitones.trim().parse::<f32>()?
+    };
+    if !semitones.is_finite() {
+        bail!("Pitch interval must be a finite number");
+    }
+    Ok(semitones)
+}
+
+/// The frequency ratio of a non-zero integer pitch multiple.
+///
+/// Positive multiples shift up the harmonic series and negative ones down
+/// the subharmonic series, so `3` is `3.0` and `-2` is `0.5`.
+pub fn pitch_multiple_to_ratio(pitch_multiple: i8) -> f32 {
+    assert!(pitch_multiple != 0);
+    if pitch_multiple < 0 {
+        1.0 / pitch_multiple.unsigned_abs() as f32
+    } else {
+        pitch_multiple as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use test_case::test_case;
+
+    #[test_case("1.5", Some(1.5) ; "bare ratio")]
+    #[test_case("12st", Some(2.0) ; "octave up in semitones")]
+    #[test_case("-12st", Some(0.5) ; "octave down in semitones")]
+    #[test_case("7st", Some(1.4983071) ; "fifth up in semitones")]
+    #[test_case("-120c", Some(0.933033) ; "negative cents")]
+    #[test_case("1200c", Some(2.0) ; "octave up in cents")]
+    #[test_case("0", None ; "zero ratio fails")]
+    #[test_case("-1.5", None ; "negative ratio fails")]
+    #[test_case("fifth", None ; "nonsense fails")]
+    fn test_parse_pitch(pitch_str: &str, expected_result_as_opt: Option<f32>) {
+        match (parse_pitch(pitch_str).ok(), expected_result_as_opt) {
+            (Some(result), Some(expected)) => assert_almost_eq(result, expected),
+            (result, expected) => assert_eq!(result, expected),
+        }
+    }
+
+    #[test_case("5", Some(5.0) ; "bare semitones")]
+    #[test_case("-3st", Some(-3.0) ; "semitones with suffix")]
+    #[test_case("-120c", Some(-1.2) ; "cents")]
+    #[test_case("up", None ; "nonsense fails")]
+    fn test_parse_semitones(interval_str: &str, expected_result_as_opt: Option<f32>) {
+        match (parse_semitones(interval_str).ok(), expected_result_as_opt) {
+            (Some(result), Some(expected)) => assert_almost_eq(result, expected),
+            (result, expected) => assert_eq!(result, expected),
+        }
+    }
+
+    #[test_case(1, 1.0 ; "unison")]
+    #[test_case(3, 3.0 ; "harmonic")]
+    #[test_case(-1, 1.0 ; "negative unison")]
+    #[test_case(-4, 0.25 ; "subharmonic")]
+    fn test_pitch_multiple_to_ratio(pitch_multiple: i8, expected: f32) {
+        assert_almost_eq(pitch_multiple_to_ratio(pitch_multiple), expected);
+    }
+}
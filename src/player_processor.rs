@@ -81,11 +81,8 @@ impl AudioOutputProcessor {
         output_stream.play().expect("failed to start output stream");
 
         loop {
-            match self.handle_control_messages(&ctrl_rx)? {
-                ProcessorState::Finished => {
-                    break;
-                }
-                _ => {}
+            if let ProcessorState::Finished = self.handle_control_messages(&ctrl_rx)? {
+                break;
             }
             if self
                 .mixer
@@ -148,7 +145,7 @@ impl Processor<AudioOutputProcessorControlMessage> for AudioOutputProcessor {
                 } => {
                     let mut mixer = self.mixer.lock().unwrap();
                     mixer.insert_layer(id, bus, shutdown_when_finished)?;
-                    mixer.fade_in_out(id, fade.clone(), fade)?;
+                    mixer.fade_in_out(id, fade, fade)?;
                     Ok(ProcessorState::Running)
                 }
             },
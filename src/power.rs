@@ -13,7 +13,7 @@ pub fn audio_power(audio: &[f32]) -> f32 {
         .map(|s| s.abs())
         .max_by(|x, y| x.partial_cmp(y).unwrap())
         .unwrap();
-    return relative_decibels(raw_amp);
+    relative_decibels(raw_amp)
 }
 
 #[cfg(test)]
@@ -1,3 +1,5 @@
+//! Simple audio recording
+
 use cpal::{
     self,
     traits::{DeviceTrait, HostTrait, StreamTrait},
@@ -10,8 +12,6 @@ use crate::audio::{Audio, AudioSpec};
 use crate::cpal_utils;
 use crate::power;
 
-/// Simple audio recording
-
 const NOISE_ANALYSIS_WINDOW_SIZE: Duration = Duration::from_millis(100);
 const NOISE_THRESHOLD_PERCENTILE: usize = 30;
 
@@ -44,11 +44,8 @@ pub fn record_audio(audio_spec: &AudioSpec) -> Audio {
             move |data: &[f32], &_: &cpal::InputCallbackInfo| {
                 // react to stream events and read or write stream data here.
                 for sample in data.iter() {
-                    match raw_samples_sender.send(*sample) {
-                        Err(e) => {
-                            error!("failed to send recorded sample: {}", e);
-                        }
-                        _ => (),
+                    if let Err(e) = raw_samples_sender.send(*sample) {
+                        error!("failed to send recorded sample: {}", e);
                     }
                 }
             },
@@ -73,7 +70,7 @@ pub fn record_audio(audio_spec: &AudioSpec) -> Audio {
 }
 
 fn collect_samples(spec: &AudioSpec, raw_samples_receiver: mpsc::Receiver<f32>) -> Audio {
-    let mut audio = Audio::from_spec(&spec);
+    let mut audio = Audio::from_spec(spec);
     for (i, sample) in raw_samples_receiver.try_iter().enumerate() {
         audio.data[i % spec.channels as usize].push(sample);
     }
@@ -105,7 +102,7 @@ fn chunked_audio_power(audio: &Audio, bin_dur: Duration) -> Vec<(usize, f32)> {
                     &channel[bin_start_sample..(bin_start_sample + bin_length).min(sample_dur)],
                 )
             })
-            .max_by(|x, y| x.partial_cmp(&y).unwrap())
+            .max_by(|x, y| x.partial_cmp(y).unwrap())
             .unwrap();
         bins.push((bin_start_sample, *bin_amplitude));
     }
@@ -146,7 +143,7 @@ fn auto_split_mono(audio: &mut Audio) {
 /// Analyze audio to determine when the recording subject begins and ends,
 /// and crop to fit it
 fn autocrop_audio(audio: &mut Audio, analysis_window: Duration, threshold_percentile: usize) {
-    let amplitudes = chunked_audio_power(&audio, analysis_window);
+    let amplitudes = chunked_audio_power(audio, analysis_window);
     let autocrop_points = determine_autocrop_points(&amplitudes, threshold_percentile);
     if autocrop_points.is_none() {
         return;
@@ -162,10 +159,10 @@ fn autocrop_audio(audio: &mut Audio, analysis_window: Duration, threshold_percen
     audio.clip_in_place(Some(start_time), Some(clip_dur));
 }
 
-fn determine_noise_threshold(amplitudes: &Vec<(usize, f32)>, threshold_percentile: usize) -> f32 {
+fn determine_noise_threshold(amplitudes: &[(usize, f32)], threshold_percentile: usize) -> f32 {
     debug_assert!(!amplitudes.is_empty());
     debug_assert!(threshold_percentile <= 100);
-    let mut working_amplitudes = amplitudes.clone();
+    let mut working_amplitudes = amplitudes.to_vec();
     working_amplitudes.sort_unstable_by(|x, y| x.1.partial_cmp(&y.1).unwrap());
     let threshold_index =
         ((threshold_percentile as f32 / 100.0) * working_amplitudes.len() as f32).floor() as usize;
@@ -174,7 +171,7 @@ fn determine_noise_threshold(amplitudes: &Vec<(usize, f32)>, threshold_percentil
 
 // assumes `amplitudes` is sorted by sample number
 fn determine_autocrop_points(
-    amplitudes: &Vec<(usize, f32)>,
+    amplitudes: &[(usize, f32)],
     threshold_percentile: usize,
 ) -> Option<(usize, usize)> {
     let noise_threshold = determine_noise_threshold(amplitudes, threshold_percentile);
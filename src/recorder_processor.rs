@@ -85,11 +85,8 @@ impl RecorderProcessor {
             if self.finished.load(Ordering::Relaxed) {
                 break;
             }
-            match self.handle_control_messages(&ctrl_rx)? {
-                ProcessorState::Finished => {
-                    break;
-                }
-                _ => {}
+            if let ProcessorState::Finished = self.handle_control_messages(&ctrl_rx)? {
+                break;
             }
             thread::sleep(RECORDER_POLL);
         }
@@ -97,11 +94,7 @@ impl RecorderProcessor {
     }
 }
 
-fn send_samples_from_raw_input(
-    buf: &[f32],
-    n_channels: u16,
-    channel_senders: &Vec<Sender<Vec<f32>>>,
-) {
+fn send_samples_from_raw_input(buf: &[f32], n_channels: u16, channel_senders: &[Sender<Vec<f32>>]) {
     // optimisation opportunity here by creating inner vecs with capacities
     let mut channels: Vec<Vec<f32>> = (0..n_channels).map(|_| vec![]).collect();
     for buffer_interleaved_samples in buf.chunks(n_channels as usize) {
@@ -1,39 +1,207 @@
-use crate::math::lerp;
+use anyhow::{bail, Error, Result};
+use std::f64::consts::PI;
+use std::str::FromStr;
 
-pub fn resample(samples: &[f32], factor: i8) -> Vec<f32> {
-    if factor == 1 {
-        samples.to_vec()
-    } else if factor > 1 {
-        resample_faster(samples, factor as usize)
-    } else if factor < -1 {
-        resample_slower(samples, factor.abs() as usize)
-    } else {
-        panic!("invalid resample factor");
-    }
+/// Kernel table entries per zero crossing; positions between them are interpolated
+const TABLE_OVERSAMPLING: usize = 512;
+
+/// Trade-off between resampling filter length and aliasing rejection
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    Fast,
+    #[default]
+    Balanced,
+    Best,
 }
 
-fn resample_faster(samples: &[f32], factor: usize) -> Vec<f32> {
-    debug_assert!(factor > 1);
-    samples.iter().step_by(factor).map(|s| *s).collect()
+impl ResampleQuality {
+    /// Zero crossings of the sinc kernel on either side of its center
+    fn zero_crossings(&self) -> usize {
+        match self {
+            ResampleQuality::Fast => 8,
+            ResampleQuality::Balanced => 16,
+            ResampleQuality::Best => 48,
+        }
+    }
+
+    /// Cutoff as a fraction of the output Nyquist frequency when downsampling,
+    /// leaving room for the filter's transition band
+    fn rolloff(&self) -> f64 {
+        match self {
+            ResampleQuality::Fast => 0.85,
+            ResampleQuality::Balanced => 0.9,
+            ResampleQuality::Best => 0.95,
+        }
+    }
 }
 
-fn resample_slower(samples: &[f32], factor: usize) -> Vec<f32> {
-    debug_assert!(factor > 1);
-    let mut result = Vec::with_capacity(samples.len() * factor);
-    for i in 0..samples.len() - 1 {
-        let current_src_sample = samples[i];
-        let next_src_sample = samples[i + 1];
-        for j in 0..factor {
-            result.push(lerp(
-                current_src_sample,
-                next_src_sample,
-                j as f32 / factor as f32,
-            ))
+impl FromStr for ResampleQuality {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fast" => Ok(ResampleQuality::Fast),
+            "balanced" => Ok(ResampleQuality::Balanced),
+            "best" => Ok(ResampleQuality::Best),
+            _ => bail!(
+                "Invalid resample quality '{}'; expected 'fast', 'balanced' or 'best'",
+                s
+            ),
         }
     }
+}
+
+/// Resample a whole buffer, reading `ratio` input samples per output sample
+pub fn resample(samples: &[f32], ratio: f32, quality: ResampleQuality) -> Vec<f32> {
+    let mut resampler = Resampler::new(ratio, quality);
+    let output_len = (samples.len() as f64 / ratio as f64).round() as usize;
+    let (result, _) = resampler.process(samples, output_len);
     result
 }
 
+/// Streaming band-limited resampler for arbitrary ratios
+///
+/// Reads `ratio` input samples per output sample, so ratios above 1 raise
+/// pitch. Interpolation uses a Blackman-windowed sinc kernel; when reading
+/// faster than real time the kernel's cutoff is lowered to the output's
+/// Nyquist frequency so nothing aliases. The fractional read position
+/// carries over between calls, so consecutive blocks join seamlessly.
+pub struct Resampler {
+    ratio: f64,
+    /// Ratio the next call to `process` glides to
+    target_ratio: f64,
+    position: f64,
+    rolloff: f64,
+    zero_crossings: usize,
+    kernel_table: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(ratio: f32, quality: ResampleQuality) -> Resampler {
+        assert!(ratio > 0.0);
+        let zero_crossings = quality.zero_crossings();
+        Resampler {
+            ratio: ratio as f64,
+            target_ratio: ratio as f64,
+            position: 0.0,
+            rolloff: quality.rolloff(),
+            zero_crossings,
+            kernel_table: windowed_sinc_table(zero_crossings),
+        }
+    }
+
+    /// Glide linearly to `ratio` over the course of the next `process` call
+    pub fn set_ratio(&mut self, ratio: f32) {
+        assert!(ratio > 0.0);
+        self.target_ratio = ratio as f64;
+    }
+
+    /// How many input samples `process` needs to produce `output_len` samples
+    pub fn input_needed(&self, output_len: usize) -> usize {
+        let last_position = self.position
+            + (0..output_len.saturating_sub(1))
+                .map(|i| self.ratio_at(i, output_len))
+                .sum::<f64>();
+        last_position.floor() as usize + self.half_width(self.ratio.max(self.target_ratio)) + 1
+    }
+
+    /// Produce `output_len` samples read from the start of `input`.
+    ///
+    /// Also returns how many leading input samples are no longer needed;
+    /// the next call expects `input` to start after them. Samples missing
+    /// from either end of `input` are treated as silence.
+    pub fn process(&mut self, input: &[f32], output_len: usize) -> (Vec<f32>, usize) {
+        let mut output = vec![0.0; output_len];
+        let consumed = self.process_into(input, &mut output);
+        (output, consumed)
+    }
+
+    /// Like `process`, but fills `output` instead of allocating it
+    pub fn process_into(&mut self, input: &[f32], output: &mut [f32]) -> usize {
+        let output_len = output.len();
+        let mut position = self.position;
+        for (i, out) in output.iter_mut().enumerate() {
+            let ratio = self.ratio_at(i, output_len);
+            *out = self.interpolate(input, position, self.cutoff(ratio));
+            position += ratio;
+        }
+        self.ratio = self.target_ratio;
+        let consumed = (position.floor() as usize)
+            .saturating_sub(self.half_width(self.ratio))
+            .min(input.len());
+        self.position = position - consumed as f64;
+        consumed
+    }
+
+    /// The ratio for the `i`th of `output_len` samples gliding to the target ratio
+    #[inline]
+    fn ratio_at(&self, i: usize, output_len: usize) -> f64 {
+        self.ratio + (self.target_ratio - self.ratio) * i as f64 / output_len as f64
+    }
+
+    /// The kernel's cutoff as a fraction of the input Nyquist frequency
+    #[inline]
+    fn cutoff(&self, ratio: f64) -> f64 {
+        // reading at or below real time can't alias, so there's no need to filter anything
+        if ratio > 1.0 {
+            self.rolloff / ratio
+        } else {
+            1.0
+        }
+    }
+
+    /// Input samples needed on either side of a read position
+    #[inline]
+    fn half_width(&self, ratio: f64) -> usize {
+        (self.zero_crossings as f64 / self.cutoff(ratio)).ceil() as usize
+    }
+
+    #[inline]
+    fn interpolate(&self, samples: &[f32], position: f64, cutoff: f64) -> f32 {
+        let half_width = (self.zero_crossings as f64 / cutoff).ceil() as isize;
+        let center = position.floor() as isize;
+        let first = (center - half_width + 1).max(0);
+        let last = (center + half_width).min(samples.len() as isize - 1);
+        let mut sum = 0.0;
+        for i in first..=last {
+            sum += samples[i as usize] * self.kernel(position - i as f64, cutoff);
+        }
+        sum * cutoff as f32
+    }
+
+    /// The kernel's value `offset` input samples from its center
+    #[inline]
+    fn kernel(&self, offset: f64, cutoff: f64) -> f32 {
+        let table_pos = offset.abs() * cutoff * TABLE_OVERSAMPLING as f64;
+        let index = table_pos as usize;
+        if index + 1 >= self.kernel_table.len() {
+            return 0.0;
+        }
+        let frac = (table_pos - index as f64) as f32;
+        self.kernel_table[index] + (self.kernel_table[index + 1] - self.kernel_table[index]) * frac
+    }
+}
+
+/// One side of a Blackman-windowed sinc kernel spanning `zero_crossings`,
+/// sampled `TABLE_OVERSAMPLING` times per zero crossing
+fn windowed_sinc_table(zero_crossings: usize) -> Vec<f32> {
+    let len = zero_crossings * TABLE_OVERSAMPLING + 1;
+    (0..len)
+        .map(|i| {
+            let x = i as f64 / TABLE_OVERSAMPLING as f64;
+            let sinc = if i == 0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            // the upper half of a Blackman window centered on the kernel
+            let w = PI * (1.0 + x / zero_crossings as f64);
+            let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+            (sinc * window) as f32
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -42,14 +210,103 @@ mod test {
     #[test]
     fn test_resample_noop() {
         let v = vec![1.0, 2.0, 3.0];
-        assert_almost_eq_by_element(resample(&v, 1), v);
+        assert_almost_eq_by_element(resample(&v, 1.0, ResampleQuality::default()), v);
+    }
+
+    #[test]
+    fn test_resample_output_len() {
+        let v = vec![0.0; 100];
+        assert_eq!(resample(&v, 3.0, ResampleQuality::default()).len(), 33);
+        assert_eq!(resample(&v, 0.5, ResampleQuality::default()).len(), 200);
+    }
+
+    #[test]
+    fn resampler_unit_ratio_is_identity() {
+        let input: Vec<f32> = (0..64).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut resampler = Resampler::new(1.0, ResampleQuality::default());
+        let (output, consumed) = resampler.process(&input, 32);
+        assert_almost_eq_by_element(output, input[..32].to_vec());
+        assert_eq!(consumed, 16);
+    }
+
+    #[test]
+    fn resampler_blocks_join_seamlessly() {
+        let input: Vec<f32> = (0..256).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut whole = Resampler::new(1.7, ResampleQuality::default());
+        let (expected, _) = whole.process(&input, 100);
+
+        let mut blocks = Resampler::new(1.7, ResampleQuality::default());
+        let (mut output, consumed) = blocks.process(&input, 45);
+        let (rest, _) = blocks.process(&input[consumed..], 55);
+        output.extend(rest);
+        assert_almost_eq_by_element(output, expected);
+    }
+
+    #[test]
+    fn resampler_glides_to_new_ratio() {
+        // a slow ramp is well within the passband, so reads land on its values
+        let input: Vec<f32> = (0..256).map(|i| i as f32 * 0.001).collect();
+        let mut resampler = Resampler::new(0.5, ResampleQuality::default());
+        resampler.set_ratio(1.0);
+        let (output, consumed) = resampler.process(&input, 100);
+        // after i steps the read position has moved by 0.5 * i + 0.5 * i * (i - 1) / 2 / 100
+        for i in [40, 70, 99] {
+            let position = 0.5 * i as f32 + 0.25 * (i * (i - 1)) as f32 / 100.0;
+            assert_almost_eq(output[i], position * 0.001);
+        }
+        // and then stays at the new ratio
+        let (output, _) = resampler.process(&input[consumed..], 100);
+        assert_almost_eq(output[10] - output[0], 10.0 * 0.001);
+    }
+
+    #[test]
+    fn resampler_input_needed_covers_kernel() {
+        let resampler = Resampler::new(1.0, ResampleQuality::Fast);
+        // last read position is 3, which also needs the 8 samples after it
+        assert_eq!(resampler.input_needed(4), 12);
+    }
+
+    #[test]
+    fn octave_up_rejects_sweep_above_new_nyquist() {
+        // everything above 11025Hz would alias when reading at twice the speed
+        let sweep = generate_sweep(12000.0, 21000.0, 44100, 44100);
+        for (quality, max_db) in [
+            (ResampleQuality::Fast, -70.0),
+            (ResampleQuality::Balanced, -80.0),
+            (ResampleQuality::Best, -100.0),
+        ] {
+            let output = resample(&sweep, 2.0, quality);
+            let power_db = relative_power_db(trim_edges(&output), &sweep);
+            assert!(power_db < max_db, "{} dB at {:?}", power_db, quality);
+        }
     }
 
     #[test]
-    fn test_resample_faster() {
-        assert_almost_eq_by_element(
-            resample_faster(&vec![1.0, 2.0, 3.0, 4.0], 2),
-            vec![1.0, 3.0],
-        );
+    fn fractional_ratio_rejects_sweep_above_new_nyquist() {
+        let sweep = generate_sweep(16000.0, 21000.0, 44100, 44100);
+        let output = resample(&sweep, 1.5, ResampleQuality::Balanced);
+        assert!(relative_power_db(trim_edges(&output), &sweep) < -80.0);
+    }
+
+    #[test]
+    fn resampling_keeps_sweep_in_passband() {
+        let sweep = generate_sweep(100.0, 8000.0, 44100, 44100);
+        for ratio in [0.5, 1.5, 2.0] {
+            let output = resample(&sweep, ratio, ResampleQuality::Balanced);
+            let power_db = relative_power_db(trim_edges(&output), &sweep);
+            assert!(power_db.abs() < 0.2, "{} dB at ratio {}", power_db, ratio);
+        }
+    }
+
+    /// Ignore the edges, where the sweep starts and stops abruptly and the
+    /// kernel runs off the end of the input
+    fn trim_edges(samples: &[f32]) -> &[f32] {
+        let trim = 512;
+        &samples[trim..samples.len() - trim]
+    }
+
+    fn relative_power_db(signal: &[f32], reference: &[f32]) -> f32 {
+        let power = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>() / s.len() as f32;
+        10.0 * (power(signal) / power(reference)).log10()
     }
 }
@@ -0,0 +1,183 @@
+use crate::stretcher::{StretchPosition, Stretcher};
+use crossbeam_channel::{bounded, unbounded, Receiver};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread;
+
+/// Frames in each segment rendered by one worker
+const SEGMENT_FRAMES: usize = 256;
+
+/// Samples of input sent to a stretcher at a time by `feed`
+const FEED_CHUNK_LEN: usize = 1 << 16;
+
+/// How many worker threads each of `channels` channels gets, so that
+/// prerendering them all at once uses every core without oversubscribing
+pub fn threads_per_channel(channels: usize) -> usize {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    (cores / channels.max(1)).max(1)
+}
+
+/// Render one channel's frames ahead of its stretcher on `threads` workers.
+///
+/// The input is split into segments of frames, each rendered by its own
+/// stretcher from `make_stretcher`, which must have the same settings as
+/// the stretcher the hops are for. Workers start a few frames before their
+/// segment so the overlap-add at the seams comes out exactly as it would
+/// rendering everything in order. Segments are rendered a round at a time,
+/// one per worker, and sent on in order until the receiver is dropped.
+///
+/// Segments only come out the same as rendering in order when frames don't
+/// depend on the output before them, so this is only for random-phase
+/// resynthesis at a constant pitch, without freezes or frequency kernels.
+pub fn prerender<F>(input: Arc<[f32]>, threads: usize, make_stretcher: F) -> Receiver<Vec<f32>>
+where
+    F: Fn(Receiver<Vec<f32>>) -> Stretcher + Send + Sync + 'static,
+{
+    let threads = threads.max(1);
+    let (tx, rx) = bounded(threads);
+    thread::spawn(move || {
+        // walks through the input ahead of the workers without resynthesizing anything
+        let mut planner = make_stretcher(unbounded().1);
+        let warmup = planner.warmup_frames();
+        // positions of the frames in this round, after the warm-up frames before them
+        let mut positions: VecDeque<StretchPosition> = VecDeque::new();
+        loop {
+            for _ in 0..threads * SEGMENT_FRAMES {
+                positions.push_back(planner.position());
+                planner.skip_frames(1);
+            }
+            let round_start = positions.len() - threads * SEGMENT_FRAMES;
+            let segments: Vec<Vec<f32>> = thread::scope(|scope| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|i| {
+                        let segment_start = round_start + i * SEGMENT_FRAMES;
+                        let worker_start = segment_start.saturating_sub(warmup);
+                        let start = positions[worker_start];
+                        let last = positions[segment_start + SEGMENT_FRAMES - 1];
+                        let skip = segment_start - worker_start;
+                        let (input, make_stretcher) = (&input, &make_stretcher);
+                        scope
+                            .spawn(move || render_segment(make_stretcher, input, start, last, skip))
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+            for segment in segments {
+                if tx.send(segment).is_err() {
+                    return;
+                }
+            }
+            positions.drain(..positions.len() - warmup.min(positions.len()));
+        }
+    });
+    rx
+}
+
+/// Feed `input` to a stretcher a chunk at a time as it gets through it, so
+/// the input can be shared with `prerender` rather than copied
+pub fn feed(input: Arc<[f32]>) -> Receiver<Vec<f32>> {
+    let (tx, rx) = bounded(1);
+    thread::spawn(move || {
+        for chunk in input.chunks(FEED_CHUNK_LEN) {
+            if tx.send(chunk.to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Render the synthesis hops completed by the frames from `start` to `last`,
+/// leaving out the first `skip`
+fn render_segment<F>(
+    make_stretcher: &F,
+    input: &[f32],
+    start: StretchPosition,
+    last: StretchPosition,
+    skip: usize,
+) -> Vec<f32>
+where
+    F: Fn(Receiver<Vec<f32>>) -> Stretcher,
+{
+    let (tx, rx) = unbounded();
+    let mut stretcher = make_stretcher(rx);
+    // just enough input for the segment's analysis windows
+    let first_sample = (start.input_pos.floor() as usize).min(input.len());
+    let end_sample = (last.input_pos.floor() as usize + stretcher.window_len()).min(input.len());
+    tx.send(input[first_sample..end_sample].to_vec()).unwrap();
+    drop(tx);
+    stretcher.seek(start);
+    stretcher.render_hops(skip, last.frame - start.frame + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+    use crate::fft::{ChannelPhases, KernelClock, PhaseRandomizer, ResynthMode};
+    use crate::pitch_curve::PitchCurve;
+    use crate::resampler::ResampleQuality;
+    use crate::test_utils::*;
+    use crate::time_map::TimeMap;
+    use crate::windows;
+    use std::time::Duration;
+    use test_case::test_case;
+
+    #[test_case(None ; "without transient detection")]
+    #[test_case(Some(0.5) ; "with transient detection")]
+    fn prerendered_segments_match_rendering_in_order(transient_sensitivity: Option<f32>) {
+        let mut input = generate_noise(30000, 7);
+        for i in (1000..input.len()).step_by(4000) {
+            input[i] = 1.0;
+        }
+        let phase_randomizer = PhaseRandomizer::for_channels(2, ChannelPhases::Wide, 5)
+            .pop()
+            .unwrap();
+        let make_stretcher = move |rx| {
+            Stretcher::new(
+                AudioSpec {
+                    channels: 1,
+                    sample_rate: 44100,
+                },
+                rx,
+                TimeMap::constant(3.0),
+                0.8,
+                PitchCurve::constant(1.5),
+                ResampleQuality::default(),
+                windows::hanning(256),
+                4,
+                ResynthMode::RandomPhase,
+                transient_sensitivity,
+                phase_randomizer.clone(),
+                true,
+                Duration::from_secs(1),
+                0,
+                KernelClock::AudioTime,
+                vec![],
+            )
+        };
+
+        let render = |stretcher: &mut Stretcher| {
+            let mut output = vec![];
+            while !stretcher.is_done() {
+                output.extend(stretcher.next_window());
+            }
+            output
+        };
+        let (tx, rx) = unbounded();
+        tx.send(input.clone()).unwrap();
+        drop(tx);
+        let expected = render(&mut make_stretcher(rx));
+
+        let input: Arc<[f32]> = input.into();
+        let mut stretcher = make_stretcher(feed(input.clone()));
+        stretcher.use_prerendered(prerender(input, 3, make_stretcher));
+        let output = render(&mut stretcher);
+        // several segments' worth of output
+        assert!(output.len() > 4 * SEGMENT_FRAMES * 64);
+        assert_eq!(output, expected);
+    }
+}
@@ -1,5 +1,4 @@
 use libc;
-use std::mem;
 
 #[inline]
 pub fn zero_slice<T>(slc: &mut [T]) {
@@ -7,7 +6,7 @@ pub fn zero_slice<T>(slc: &mut [T]) {
         libc::memset(
             slc.as_mut_ptr() as *mut libc::c_void,
             0,
-            mem::size_of::<T>() * slc.len(),
+            std::mem::size_of_val(slc),
         );
     }
 }
@@ -1,9 +1,13 @@
 use crate::audio::AudioSpec;
 use crate::crossfade;
-use crate::fft::ReFFT;
-use crate::resampler;
+use crate::fft::{KernelClock, PhaseRandomizer, ReFFT, ResynthMode};
+use crate::formants::FormantPreserver;
+use crate::kernel::FrequencyKernel;
+use crate::pitch_curve::PitchCurve;
+use crate::resampler::{ResampleQuality, Resampler};
+use crate::time_map::TimeMap;
 use crossbeam_channel::Receiver;
-use slice_deque::SliceDeque;
+use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::time::Duration;
 // use stopwatch::Stopwatch;
@@ -12,71 +16,200 @@ use std::time::Duration;
 pub struct Stretcher {
     pub spec: AudioSpec,
     input: Receiver<Vec<f32>>,
-    input_buf: SliceDeque<f32>,
-    output_buf: SliceDeque<f32>,
-    corrected_amp_factor: f32,
-    pitch_multiple: i8,
-    amp_correction_envelope: Vec<f32>,
+    input_buf: Vec<f32>,
+    /// Input samples still to be skipped once they're received
+    input_skip: usize,
+    output_buf: Vec<f32>,
+    time_map: TimeMap,
+    amplitude: f32,
+    pitch_curve: PitchCurve,
+    /// Pitch ratio for the window of output currently being generated
+    pitch: f32,
+    resampler: Resampler,
+    /// Gain for each sample of a synthesis hop once every overlapping frame has been added
+    overlap_compensation: Vec<f32>,
+    /// Overlap compensation for frozen frames, which always have random phases
+    freeze_compensation: Vec<f32>,
     re_fft: ReFFT,
     window_len: usize,
-    half_window_len: usize,
-    samples_needed_per_window: usize,
-    sample_step_len: usize,
+    /// Output samples between consecutive resynthesized frames
+    synthesis_hop: usize,
+    /// Position of the current analysis window's start in the input, in
+    /// samples. Windows are centered on the input, so the first starts before it.
+    input_pos: f64,
+    /// Input samples between the last analysis window and the current one
+    analysis_hop: usize,
+    /// Index of the next frame to be resynthesized
+    frame: usize,
+    /// Output samples generated so far
+    output_pos: usize,
+    /// Samples still to be trimmed from the start of the output before
+    /// resampling, where the first window hangs over the start of the input
+    output_trim: usize,
+    /// Seconds of output preceding the next resynthesized frame, once resampled
+    synthesis_time: f64,
+    /// Input time in seconds at which to freeze the analysis window
+    freeze_at: Option<f64>,
+    /// Output samples after which the stretcher is done, even if input remains
+    output_limit: Option<usize>,
+    /// Whether the current freeze was asked for live with `freeze`, rather
+    /// than scheduled with `freeze_at`
+    live_freeze: bool,
+    /// Output samples generated while holding live freezes, which don't
+    /// count towards the output limit
+    live_frozen_len: f64,
+    /// Synthesis hops rendered ahead of time, which stand in for resynthesized frames
+    prerendered: Option<Receiver<Vec<f32>>>,
+    prerendered_hops: VecDeque<f32>,
     done: bool,
     buffer_dur: Duration,
 }
 
+/// How far a stretcher has got through its input, for starting another
+/// stretcher with the same settings at the same point
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StretchPosition {
+    pub frame: usize,
+    pub input_pos: f64,
+    pub analysis_hop: usize,
+}
+
 impl Stretcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         spec: AudioSpec,
         input: Receiver<Vec<f32>>,
-        factor: f32,
+        time_map: TimeMap,
         amplitude: f32,
-        pitch_multiple: i8,
+        pitch_curve: PitchCurve,
+        resample_quality: ResampleQuality,
         window: Vec<f32>,
+        overlap: usize,
+        resynth_mode: ResynthMode,
+        transient_sensitivity: Option<f32>,
+        phase_randomizer: PhaseRandomizer,
+        preserve_formants: bool,
         buffer_dur: Duration,
-        frequency_kernel_src: Option<PathBuf>,
+        channel: usize,
+        kernel_clock: KernelClock,
+        frequency_kernel_srcs: Vec<PathBuf>,
     ) -> Stretcher {
-        assert!(pitch_multiple != 0);
         let window_len = window.len();
-        let pitch_shifted_factor = if pitch_multiple < 0 {
-            factor / pitch_multiple.abs() as f32
-        } else {
-            factor * pitch_multiple.abs() as f32
-        };
-        let samples_needed_per_window = if pitch_multiple < 0 {
-            (window_len as f32 / pitch_multiple.abs() as f32).ceil() as usize
-        } else {
-            window_len * pitch_multiple.abs() as usize
+        assert!(overlap >= 2 && window_len.is_multiple_of(overlap));
+        let synthesis_hop = window_len / overlap;
+        let overlap_compensation = match resynth_mode {
+            ResynthMode::RandomPhase => {
+                crossfade::random_phase_overlap_compensation(&window, synthesis_hop)
+            }
+            // coherent phases overlap-add exactly, so only the window shape needs undoing
+            ResynthMode::PhaseVocoder(_) => {
+                crossfade::squared_window_overlap_compensation(&window, synthesis_hop)
+            }
         };
-        // correct for power lost in resynth - correction curve approx by trial and error
-        let corrected_amp_factor = (4f32).max(pitch_shifted_factor / 4.0) * amplitude;
-        let half_window_len = window_len / 2;
-        let sample_step_len = (window_len as f32 / (pitch_shifted_factor * 2.0)) as usize;
-        let amp_correction_envelope = crossfade::hanning_crossfade_compensation(window.len() / 2);
-        let re_fft = ReFFT::new(window, frequency_kernel_src);
-        let mut output_buf = SliceDeque::with_capacity(samples_needed_per_window + half_window_len);
-        output_buf.extend(vec![0.0; half_window_len]);
+        let freeze_compensation =
+            crossfade::random_phase_overlap_compensation(&window, synthesis_hop);
+        let re_fft = ReFFT::new(
+            window,
+            resynth_mode,
+            transient_sensitivity,
+            phase_randomizer,
+            preserve_formants.then(|| FormantPreserver::new(window_len, spec.sample_rate)),
+            frequency_kernel_srcs
+                .into_iter()
+                .map(|src| {
+                    FrequencyKernel::new(src, kernel_clock, spec.sample_rate, window_len, channel)
+                        .unwrap()
+                })
+                .collect(),
+        );
+        let pitch = pitch_curve.ratio_at(0.0);
+        let resampler = Resampler::new(pitch, resample_quality);
+        let mut output_buf = Vec::with_capacity(resampler.input_needed(window_len) + window_len);
+        output_buf.extend(vec![0.0; window_len - synthesis_hop]);
         Stretcher {
             spec,
             input,
-            corrected_amp_factor,
-            pitch_multiple,
-            amp_correction_envelope,
+            time_map,
+            amplitude,
+            pitch_curve,
+            pitch,
+            resampler,
+            overlap_compensation,
+            freeze_compensation,
             re_fft,
             window_len,
-            half_window_len,
-            samples_needed_per_window,
-            sample_step_len,
+            synthesis_hop,
+            input_pos: -((window_len / 2) as f64),
+            analysis_hop: 0,
+            frame: 0,
+            output_pos: 0,
+            output_trim: window_len / 2,
+            synthesis_time: 0.0,
+            freeze_at: None,
+            output_limit: None,
+            live_freeze: false,
+            live_frozen_len: 0.0,
+            prerendered: None,
+            prerendered_hops: VecDeque::new(),
             buffer_dur,
             output_buf,
-            input_buf: SliceDeque::new(),
+            // silence before the input for the first window to hang over
+            input_buf: vec![0.0; window_len / 2],
+            input_skip: 0,
             done: false,
         }
     }
 
     pub fn is_done(&self) -> bool {
-        self.done
+        match self.current_output_limit() {
+            Some(limit) => self.output_pos >= limit && !self.holds_live_freeze(),
+            // a frozen window can be held after the input runs out
+            None => self.done && !self.re_fft.is_frozen(),
+        }
+    }
+
+    /// The output limit, pushed back by however long live freezes were held
+    fn current_output_limit(&self) -> Option<usize> {
+        self.output_limit
+            .map(|limit| limit + self.live_frozen_len())
+    }
+
+    /// How many samples of output live freezes have added so far
+    pub fn live_frozen_len(&self) -> usize {
+        self.live_frozen_len.round() as usize
+    }
+
+    fn holds_live_freeze(&self) -> bool {
+        self.live_freeze && self.re_fft.is_frozen()
+    }
+
+    /// Freeze the analysis window once the input reaches `time`, sustaining
+    /// its spectrum with fresh random phases until released
+    pub fn freeze_at(&mut self, time: Duration) {
+        self.freeze_at = Some(time.as_secs_f64());
+    }
+
+    /// Freeze the analysis window at the current input position
+    pub fn freeze(&mut self) {
+        self.freeze_at = Some(0.0);
+        self.live_freeze = true;
+    }
+
+    /// Release a freeze, carrying on through the input from where it was frozen
+    pub fn release(&mut self) {
+        self.freeze_at = None;
+        self.live_freeze = false;
+        self.re_fft.release();
+    }
+
+    /// Bypass the frequency kernel at `stage` in the chain, or bring it back
+    pub fn bypass_kernel(&mut self, stage: usize, bypassed: bool) {
+        self.re_fft.bypass_kernel(stage, bypassed);
+    }
+
+    /// Finish after generating `len` samples of output
+    pub fn limit_output(&mut self, len: usize) {
+        self.output_limit = Some(len);
     }
 
     pub fn channel_bound(&self) -> usize {
@@ -84,47 +217,230 @@ impl Stretcher {
             .ceil() as usize
     }
 
+    #[cfg(test)]
     pub fn next_window(&mut self) -> Vec<f32> {
-        debug_assert!(self.output_buf.len() == self.half_window_len);
+        let mut window = vec![0.0; self.window_len];
+        let len = self.next_window_into(&mut window);
+        window.truncate(len);
+        window
+    }
+
+    /// Generate the next window of output into `window`, which must be one
+    /// window long, returning how much of it is output. The last window
+    /// stops short at the output limit.
+    pub fn next_window_into(&mut self, window: &mut [f32]) -> usize {
+        debug_assert!(window.len() == self.window_len);
+        let window_start = self.output_pos;
         // let sw = Stopwatch::start_new();
-        let mut iter_output_buf_pos = 0;
-        while self.output_buf.len() < self.samples_needed_per_window + self.half_window_len {
-            // Generate output one half-window at a time, with each step leaving a half window
-            // from the fade-out half of the window function for the next iteration to pick up.
-            self.ensure_input_samples_available(self.window_len);
-            let fft_result = self.re_fft.resynth(&self.input_buf[..self.window_len]);
-            for i in 0..self.half_window_len {
-                self.output_buf[iter_output_buf_pos + i] = (fft_result[i]
-                    + self.output_buf[iter_output_buf_pos + i])
-                    * self.amp_correction_envelope[i]
-                    * self.corrected_amp_factor;
-            }
-            self.output_buf
-                .extend_from_slice(&fft_result[self.half_window_len..]);
-            iter_output_buf_pos += self.half_window_len;
-            self.input_buf
-                .truncate_front(self.input_buf.len() - self.sample_step_len);
-        }
-        let result = resampler::resample(
-            &self.output_buf[..self.samples_needed_per_window],
-            self.pitch_multiple,
-        );
-        self.output_buf.truncate_front(self.half_window_len);
-        debug_assert!(result.len() == self.window_len);
+        // glide to the pitch at the end of this window
+        let window_end = (self.output_pos + self.window_len) as f64 / self.spec.sample_rate as f64;
+        self.pitch = self.pitch_curve.ratio_at(window_end as f32);
+        self.resampler.set_ratio(self.pitch);
+        let samples_needed = self.resampler.input_needed(self.window_len);
+        // the output buffer ends with the partial sums of frames still overlapping later ones
+        let pending_len = self.window_len - self.synthesis_hop;
+        while self.output_buf.len() < self.output_trim + samples_needed + pending_len {
+            self.add_frame();
+        }
+        self.output_buf.drain(..self.output_trim);
+        self.output_trim = 0;
+        let completed_len = self.output_buf.len() - pending_len;
+        let consumed = self
+            .resampler
+            .process_into(&self.output_buf[..completed_len], window);
+        self.output_buf.drain(..consumed);
+        self.output_pos += self.window_len;
         // debug!(
         //     "generated {} sample window in {:?}, ({:.0}X)",
-        //     result.len(),
+        //     window.len(),
         //     sw.elapsed(),
-        //     (result.len() as f32 / self.spec.sample_rate as f32) / sw.elapsed().as_secs_f32()
+        //     (window.len() as f32 / self.spec.sample_rate as f32) / sw.elapsed().as_secs_f32()
         // );
-        result
+        match self.current_output_limit() {
+            Some(limit) => limit.saturating_sub(window_start).min(self.window_len),
+            None => self.window_len,
+        }
+    }
+
+    /// Resynthesize the next frame and overlap-add it to the output.
+    ///
+    /// Output is generated one synthesis hop at a time. Each frame completes
+    /// the sum over the first hop it covers, and leaves the rest for later
+    /// frames to add to.
+    fn add_frame(&mut self) {
+        let pending_len = self.window_len - self.synthesis_hop;
+        self.ensure_input_samples_available(self.window_len);
+        if self.freeze_is_due() {
+            self.re_fft.freeze(&self.input_buf[..self.window_len]);
+        }
+        let frozen = self.re_fft.is_frozen();
+        let factor = self.current_factor();
+        let frame_pos = self.output_buf.len() - pending_len;
+        match &self.prerendered {
+            Some(prerendered) => {
+                while self.prerendered_hops.len() < self.synthesis_hop {
+                    let segment = prerendered.recv().expect("prerendering stopped early");
+                    self.prerendered_hops.extend(segment);
+                }
+                self.output_buf
+                    .resize(self.output_buf.len() + self.synthesis_hop, 0.0);
+                let completed = &mut self.output_buf[frame_pos..frame_pos + self.synthesis_hop];
+                for (out, sample) in completed
+                    .iter_mut()
+                    .zip(self.prerendered_hops.drain(..self.synthesis_hop))
+                {
+                    *out = sample;
+                }
+            }
+            None => {
+                let fft_result = self.re_fft.resynth(
+                    &self.input_buf[..self.window_len],
+                    self.analysis_hop,
+                    self.synthesis_hop,
+                    self.pitch,
+                    Duration::from_secs_f64(self.synthesis_time),
+                );
+                for (out, sample) in self.output_buf[frame_pos..].iter_mut().zip(fft_result) {
+                    *out += sample;
+                }
+                self.output_buf
+                    .extend_from_slice(&fft_result[pending_len..]);
+                let completed = &mut self.output_buf[frame_pos..frame_pos + self.synthesis_hop];
+                let compensation = if frozen {
+                    &self.freeze_compensation
+                } else {
+                    &self.overlap_compensation
+                };
+                for (out, compensation) in completed.iter_mut().zip(compensation) {
+                    *out *= compensation * self.amplitude;
+                }
+            }
+        }
+        self.synthesis_time +=
+            self.synthesis_hop as f64 / (self.spec.sample_rate as f64 * self.pitch as f64);
+        self.frame += 1;
+        if self.holds_live_freeze() {
+            self.live_frozen_len += self.synthesis_hop as f64 / self.pitch as f64;
+        }
+        if frozen {
+            // hold the input where it was frozen
+            self.analysis_hop = 0;
+            return;
+        }
+        self.advance_input_pos(factor);
+        self.skip_input(self.analysis_hop);
+    }
+
+    /// Move the analysis window one synthesis hop's worth of input along
+    fn advance_input_pos(&mut self, factor: f32) {
+        // audio is stretched further by the pitch ratio, then resampled back by it
+        let step = self.synthesis_hop as f64 / (factor * self.pitch) as f64;
+        // fractional steps accumulate so the average hop matches the factor exactly
+        let next_input_pos = self.input_pos + step;
+        self.analysis_hop = (next_input_pos.floor() - self.input_pos.floor()) as usize;
+        self.input_pos = next_input_pos;
+    }
+
+    /// Where the next frame will be analyzed
+    pub fn position(&self) -> StretchPosition {
+        StretchPosition {
+            frame: self.frame,
+            input_pos: self.input_pos,
+            analysis_hop: self.analysis_hop,
+        }
+    }
+
+    /// Carry on from `position`, as reached by a stretcher with the same
+    /// settings. The input must start at the first sample of the analysis
+    /// window there, or at the start of the input if the window hangs over it.
+    pub fn seek(&mut self, position: StretchPosition) {
+        self.input_buf = vec![0.0; (-position.input_pos.floor()).max(0.0) as usize];
+        self.frame = position.frame;
+        self.input_pos = position.input_pos;
+        self.analysis_hop = position.analysis_hop;
+        self.re_fft.seek(position.frame);
+    }
+
+    /// Move past the next `frames` frames without resynthesizing them
+    pub fn skip_frames(&mut self, frames: usize) {
+        let input_pos = self.input_pos;
+        for _ in 0..frames {
+            let factor = self.current_factor();
+            self.advance_input_pos(factor);
+        }
+        self.frame += frames;
+        self.re_fft.seek(self.frame);
+        self.skip_input((self.input_pos.floor() - input_pos.floor()) as usize);
+    }
+
+    /// Resynthesize the next `frames` frames, returning the synthesis hops
+    /// they complete after the first `skip`, before any resampling
+    pub fn render_hops(&mut self, skip: usize, frames: usize) -> Vec<f32> {
+        let pending_len = self.window_len - self.synthesis_hop;
+        let mut hops = Vec::with_capacity(frames.saturating_sub(skip) * self.synthesis_hop);
+        for i in 0..frames {
+            self.add_frame();
+            let completed_len = self.output_buf.len() - pending_len;
+            if i >= skip {
+                hops.extend_from_slice(
+                    &self.output_buf[completed_len - self.synthesis_hop..completed_len],
+                );
+            }
+            self.output_buf.drain(..completed_len);
+        }
+        hops
+    }
+
+    /// Take the synthesis hops of every frame from `hops`, in order, instead
+    /// of resynthesizing them. They're resampled into windows as usual.
+    pub fn use_prerendered(&mut self, hops: Receiver<Vec<f32>>) {
+        self.prerendered = Some(hops);
+    }
+
+    pub fn window_len(&self) -> usize {
+        self.window_len
+    }
+
+    /// Frames a stretcher started by `seek` should render and throw away
+    /// before its output matches one that got there from the start
+    pub fn warmup_frames(&self) -> usize {
+        // the first hops of a frame are summed with earlier frames'
+        let overlapping = self.window_len / self.synthesis_hop - 1;
+        overlapping + self.re_fft.memory_frames()
+    }
+
+    fn freeze_is_due(&self) -> bool {
+        match self.freeze_at {
+            Some(time) => {
+                let center = self.input_pos + (self.window_len / 2) as f64;
+                !self.re_fft.is_frozen() && center / self.spec.sample_rate as f64 >= time
+            }
+            None => false,
+        }
+    }
+
+    /// The stretch factor at the center of the current analysis window
+    fn current_factor(&self) -> f32 {
+        let center = self.input_pos + (self.window_len / 2) as f64;
+        self.time_map
+            .factor_at((center / self.spec.sample_rate as f64) as f32)
+    }
+
+    /// Drop the next `len` samples of input, including any not received yet
+    fn skip_input(&mut self, len: usize) {
+        // big speed-ups can step past everything buffered
+        let buffered = len.min(self.input_buf.len());
+        self.input_buf.drain(..buffered);
+        self.input_skip += len - buffered;
     }
 
     pub fn ensure_input_samples_available(&mut self, n: usize) {
         while self.input_buf.len() < n {
             match self.input.recv() {
                 Ok(chunk) => {
-                    self.input_buf.extend(chunk);
+                    let skipped = self.input_skip.min(chunk.len());
+                    self.input_skip -= skipped;
+                    self.input_buf.extend(&chunk[skipped..]);
                 }
                 Err(_) => {
                     self.input_buf.resize(n, 0.0);
@@ -136,18 +452,25 @@ impl Stretcher {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
     use super::*;
+    use crate::fft::ChannelPhases;
+    use crate::phase_vocoder::PhaseLocking;
     use crate::test_utils::*;
+    use crate::windows;
     use crossbeam_channel::{unbounded, Sender};
+    use test_case::test_case;
 
     #[test]
     fn ensure_input_samples_available_when_channel_closed_fills_with_zeros() {
         let (mut stretcher, tx) = basic_stretcher(1000);
         drop(tx);
-        stretcher.ensure_input_samples_available(4);
+        // the first window hangs over the start of the input
+        let lead = stretcher.input_buf.len();
+        stretcher.ensure_input_samples_available(lead + 4);
         assert_eq!(stretcher.done, true);
-        assert_almost_eq_by_element(stretcher.input_buf.to_vec(), vec![0.0; 4]);
+        assert_almost_eq_by_element(stretcher.input_buf[lead..].to_vec(), vec![0.0; 4]);
     }
 
     #[test]
@@ -155,26 +478,508 @@ mod test {
         let (mut stretcher, tx) = basic_stretcher(1000);
         tx.send(vec![1.0, 2.0, 3.0]).unwrap();
         tx.send(vec![4.0, 5.0]).unwrap();
-        stretcher.ensure_input_samples_available(4);
+        let lead = stretcher.input_buf.len();
+        stretcher.ensure_input_samples_available(lead + 4);
         assert_eq!(stretcher.done, false);
-        assert_almost_eq_by_element(stretcher.input_buf.to_vec(), vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_almost_eq_by_element(
+            stretcher.input_buf[lead..].to_vec(),
+            vec![1.0, 2.0, 3.0, 4.0, 5.0],
+        );
     }
 
-    fn basic_stretcher(window_len: usize) -> (Stretcher, Sender<Vec<f32>>) {
-        let (tx, rx) = unbounded();
-        let stretcher = Stretcher::new(
-            AudioSpec {
-                channels: 2,
-                sample_rate: 44100,
-            },
-            rx,
-            1.0,
-            1.0,
-            1,
-            vec![1.0; window_len],
-            Duration::from_secs(1),
-            None,
+    #[test_case(PhaseLocking::Off, 2 ; "without phase locking")]
+    #[test_case(PhaseLocking::Identity, 2 ; "with identity phase locking")]
+    #[test_case(PhaseLocking::Scaled, 2 ; "with scaled phase locking")]
+    #[test_case(PhaseLocking::Identity, 4 ; "at 4x overlap")]
+    #[test_case(PhaseLocking::Identity, 8 ; "at 8x overlap")]
+    fn phase_vocoder_at_unit_factor_reconstructs_input(locking: PhaseLocking, overlap: usize) {
+        let window_len = 1024;
+        let input = generate_sine(440.0, 44100, window_len * 8);
+        let (mut stretcher, tx) = TestStretcher {
+            overlap,
+            resynth_mode: ResynthMode::PhaseVocoder(locking),
+            // a periodic window's overlapping copies sum to a constant at any of these hops
+            ..TestStretcher::new(windows::hanning(window_len + 1)[..window_len].to_vec())
+        }
+        .build();
+        tx.send(input.clone()).unwrap();
+        let output: Vec<f32> = (0..4).flat_map(|_| stretcher.next_window()).collect();
+        // until the first frame has been fully overlapped, fewer frames contribute
+        let ramp_len = window_len - window_len / overlap;
+        assert_almost_eq_by_element(
+            output[ramp_len..].to_vec(),
+            input[ramp_len..output.len()].to_vec(),
         );
-        (stretcher, tx)
+    }
+
+    #[test_case(2, 1.0 ; "at 2x overlap without stretching")]
+    #[test_case(2, 40.0 ; "at 2x overlap with a heavy stretch")]
+    #[test_case(4, 1.0 ; "at 4x overlap without stretching")]
+    #[test_case(4, 40.0 ; "at 4x overlap with a heavy stretch")]
+    #[test_case(8, 8.0 ; "at 8x overlap")]
+    fn random_phase_keeps_noise_level(overlap: usize, factor: f32) {
+        let window_len = 1024;
+        let input = generate_noise(44100, 3);
+        let (mut stretcher, tx) = TestStretcher {
+            overlap,
+            time_map: TimeMap::constant(factor),
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(input.clone()).unwrap();
+        let output: Vec<f32> = (0..32).flat_map(|_| stretcher.next_window()).collect();
+        let gain_db = 20.0 * (rms(&output[window_len..]) / rms(&input)).log10();
+        assert!(gain_db.abs() < 1.0, "{} dB", gain_db);
+    }
+
+    #[test_case(ResynthMode::RandomPhase ; "with random phases")]
+    #[test_case(ResynthMode::PhaseVocoder(PhaseLocking::Identity) ; "with phase vocoder")]
+    fn transient_detection_keeps_impulses_sharp(resynth_mode: ResynthMode) {
+        let (input_sharpness, output_sharpness) = impulse_sharpness(resynth_mode, Some(0.5));
+        // the next frame still smears half of each impulse with random phases
+        assert!(output_sharpness > input_sharpness * 0.5);
+    }
+
+    #[test]
+    fn random_phases_smear_impulses_without_transient_detection() {
+        let (input_sharpness, output_sharpness) = impulse_sharpness(ResynthMode::RandomPhase, None);
+        assert!(output_sharpness < input_sharpness * 0.5);
+    }
+
+    /// Stretch an impulse train 2x and return the crest factors of the input and output
+    fn impulse_sharpness(
+        resynth_mode: ResynthMode,
+        transient_sensitivity: Option<f32>,
+    ) -> (f32, f32) {
+        let window_len = 2048;
+        // a quiet tone underneath gives phase vocoder resynthesis peaks to lock to
+        let mut input: Vec<f32> = generate_sine(440.0, 44100, window_len * 16)
+            .iter()
+            .map(|s| s * 0.05)
+            .collect();
+        for i in (window_len / 2..input.len()).step_by(window_len * 2) {
+            input[i] = 1.0;
+        }
+        let input_sharpness = crest_factor(&input);
+        let (mut stretcher, tx) = TestStretcher {
+            time_map: TimeMap::constant(2.0),
+            resynth_mode,
+            transient_sensitivity,
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(input).unwrap();
+        let output: Vec<f32> = (0..24).flat_map(|_| stretcher.next_window()).collect();
+        (input_sharpness, crest_factor(&output))
+    }
+
+    #[test_case(1.5 ; "up a fifth")]
+    #[test_case(0.8 ; "down a major third")]
+    fn fractional_pitch_shift_scales_frequency(pitch: f32) {
+        let window_len = 2048;
+        let input = generate_sine(440.0, 44100, window_len * 16);
+        let (mut stretcher, tx) = TestStretcher {
+            pitch_curve: PitchCurve::constant(pitch),
+            resynth_mode: ResynthMode::PhaseVocoder(PhaseLocking::Identity),
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(input).unwrap();
+        let output: Vec<f32> = (0..8).flat_map(|_| stretcher.next_window()).collect();
+        // skip the fade in of the first half window
+        let steady = &output[window_len..];
+        let measured_freq = zero_crossings(steady) as f32 / 2.0 / (steady.len() as f32 / 44100.0);
+        assert!((measured_freq / (440.0 * pitch) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn pitch_curve_glides_over_output() {
+        let window_len = 2048;
+        let input = generate_sine(440.0, 44100, window_len * 16);
+        let (mut stretcher, tx) = TestStretcher {
+            pitch_curve: "0.2, 0; 0.5, 12".parse().unwrap(),
+            resynth_mode: ResynthMode::PhaseVocoder(PhaseLocking::Identity),
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(input).unwrap();
+        drop(tx);
+        let output: Vec<f32> = (0..16).flat_map(|_| stretcher.next_window()).collect();
+        let freq_between = |start: f32, end: f32| {
+            let steady = &output[(start * 44100.0) as usize..(end * 44100.0) as usize];
+            zero_crossings(steady) as f32 / 2.0 / (end - start)
+        };
+        assert!((freq_between(0.05, 0.15) / 440.0 - 1.0).abs() < 0.02);
+        assert!((freq_between(0.3, 0.4) / 440.0 - 1.0).abs() > 0.1);
+        assert!((freq_between(0.6, 0.7) / 880.0 - 1.0).abs() < 0.02);
+    }
+
+    #[test_case(ChannelPhases::Linked, 0.9, 1.0 ; "linked channels stay correlated")]
+    #[test_case(ChannelPhases::Wide, -0.2, 0.2 ; "wide channels decorrelate")]
+    fn channel_phases_set_stereo_correlation(
+        channel_phases: ChannelPhases,
+        min_correlation: f32,
+        max_correlation: f32,
+    ) {
+        let window_len = 1024;
+        // a dry source panned left of center, with a delayed echo in the right channel
+        let source: Vec<f32> = generate_sine(440.0, 44100, window_len * 16)
+            .iter()
+            .zip(generate_sine(1234.5, 44100, window_len * 16))
+            .map(|(a, b)| a + b)
+            .collect();
+        let left = source.clone();
+        let mut right = vec![0.0; 20];
+        right.extend(source[..source.len() - 20].iter().map(|s| s * 0.5));
+        let randomizers = PhaseRandomizer::for_channels(2, channel_phases, 7);
+        let outputs: Vec<Vec<f32>> = [left, right]
+            .into_iter()
+            .zip(randomizers)
+            .map(|(input, phase_randomizer)| {
+                let (mut stretcher, tx) = TestStretcher {
+                    time_map: TimeMap::constant(4.0),
+                    phase_randomizer,
+                    ..TestStretcher::new(windows::hanning(window_len))
+                }
+                .build();
+                tx.send(input).unwrap();
+                (0..16).flat_map(|_| stretcher.next_window()).collect()
+            })
+            .collect();
+        // compare the echo-aligned channels
+        let correlation = correlation(&outputs[0][..outputs[0].len() - 20], &outputs[1][20..]);
+        assert!(
+            correlation > min_correlation && correlation < max_correlation,
+            "correlation {}",
+            correlation
+        );
+    }
+
+    #[test]
+    fn seeded_renders_are_identical() {
+        let render = |seed| {
+            let (mut stretcher, tx) = TestStretcher {
+                time_map: TimeMap::constant(3.0),
+                phase_randomizer: PhaseRandomizer::for_channels(1, ChannelPhases::default(), seed)
+                    .pop()
+                    .unwrap(),
+                ..TestStretcher::new(windows::hanning(1024))
+            }
+            .build();
+            tx.send(generate_noise(44100, 5)).unwrap();
+            (0..16)
+                .flat_map(|_| stretcher.next_window())
+                .collect::<Vec<f32>>()
+        };
+        assert_eq!(render(42), render(42));
+        assert_ne!(render(42), render(43));
+    }
+
+    #[test]
+    fn wide_channels_of_nearby_seeds_differ() {
+        let render = |seed, channel| {
+            let (mut stretcher, tx) = TestStretcher {
+                time_map: TimeMap::constant(3.0),
+                phase_randomizer: PhaseRandomizer::for_channels(2, ChannelPhases::Wide, seed)
+                    .remove(channel),
+                ..TestStretcher::new(windows::hanning(1024))
+            }
+            .build();
+            tx.send(generate_noise(44100, 5)).unwrap();
+            (0..4)
+                .flat_map(|_| stretcher.next_window())
+                .collect::<Vec<f32>>()
+        };
+        assert_ne!(render(1, 0), render(0, 1));
+        assert_ne!(render(0, 0), render(0, 1));
+    }
+
+    #[test]
+    fn freeze_sustains_window_until_released() {
+        let window_len = 1024;
+        let mut input = generate_sine(440.0, 44100, window_len * 8);
+        input.extend(vec![0.0; window_len * 8]);
+        let (mut stretcher, tx) = TestStretcher::new(windows::hanning(window_len)).build();
+        stretcher.freeze_at(Duration::from_secs_f32(0.05));
+        tx.send(input).unwrap();
+        drop(tx);
+        // carry on well past the end of the input
+        let output: Vec<f32> = (0..64).flat_map(|_| stretcher.next_window()).collect();
+        assert!(!stretcher.is_done());
+        let tail = &output[output.len() - window_len * 8..];
+        assert!((rms(tail) - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.1);
+        let spectrum = magnitude_spectrum(tail);
+        assert!((loudest_frequency(&spectrum, 44100, 100.0, 2000.0) - 440.0).abs() < 20.0);
+
+        stretcher.release();
+        let mut remaining_windows = 0;
+        while !stretcher.is_done() {
+            stretcher.next_window();
+            remaining_windows += 1;
+        }
+        assert!(remaining_windows < 16);
+    }
+
+    #[test]
+    fn live_freeze_does_not_count_towards_output_limit() {
+        let window_len = 1024;
+        let (mut stretcher, tx) = basic_stretcher(window_len);
+        stretcher.limit_output(window_len * 8);
+        tx.send(generate_noise(window_len * 8, 3)).unwrap();
+        drop(tx);
+        stretcher.next_window();
+        stretcher.next_window();
+        stretcher.freeze();
+        // hold the freeze past the output limit
+        for _ in 0..16 {
+            stretcher.next_window();
+        }
+        assert!(!stretcher.is_done());
+
+        stretcher.release();
+        let mut remaining_windows = 0;
+        while !stretcher.is_done() {
+            stretcher.next_window();
+            remaining_windows += 1;
+        }
+        // the rest of the input still comes out
+        assert!((5..=7).contains(&remaining_windows));
+    }
+
+    #[test]
+    fn output_limit_finishes_before_input_runs_out() {
+        let (mut stretcher, tx) = basic_stretcher(1024);
+        stretcher.limit_output(5000);
+        tx.send(vec![0.0; 44100]).unwrap();
+        let windows =
+            std::iter::from_fn(|| (!stretcher.is_done()).then(|| stretcher.next_window())).count();
+        assert_eq!(windows, 5);
+    }
+
+    #[test_case(256, 1.0 ; "small window unstretched")]
+    #[test_case(256, 7.3 ; "small window slowed down")]
+    #[test_case(1024, 0.3 ; "sped up")]
+    #[test_case(1024, 2.5 ; "slowed down")]
+    #[test_case(4096, 0.05 ; "big window sped up a lot")]
+    #[test_case(4096, 3.7 ; "big window slowed down")]
+    fn output_is_exactly_as_long_as_stretched_input(window_len: usize, factor: f32) {
+        let input_len = 10007;
+        let time_map = TimeMap::constant(factor);
+        let expected_len = time_map.stretched_len(input_len, 44100);
+        let (mut stretcher, tx) = TestStretcher {
+            time_map,
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        stretcher.limit_output(expected_len);
+        tx.send(generate_noise(input_len, 1)).unwrap();
+        drop(tx);
+        let mut output = vec![];
+        while !stretcher.is_done() {
+            output.extend(stretcher.next_window());
+        }
+        assert_eq!(output.len(), expected_len);
+    }
+
+    #[test_case(1.0 ; "unstretched")]
+    #[test_case(4.0 ; "slowed down")]
+    #[test_case(0.5 ; "sped up")]
+    fn output_starts_aligned_with_input(factor: f32) {
+        // a click stays where it was, in stretched time
+        let window_len = 1024;
+        let mut input = vec![0.0; 8192];
+        input[3000] = 1.0;
+        let (mut stretcher, tx) = TestStretcher {
+            time_map: TimeMap::constant(factor),
+            resynth_mode: ResynthMode::PhaseVocoder(PhaseLocking::Identity),
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(input).unwrap();
+        drop(tx);
+        let output: Vec<f32> = (0..48).flat_map(|_| stretcher.next_window()).collect();
+        // the click is smeared out by each frame it's in, evenly around where it belongs
+        let energy: f32 = output.iter().map(|s| s * s).sum();
+        let centroid = output
+            .iter()
+            .enumerate()
+            .map(|(i, s)| i as f32 * s * s)
+            .sum::<f32>()
+            / energy;
+        let expected = 3000.0 * factor;
+        assert!(
+            (centroid - expected).abs() < window_len as f32 / 8.0,
+            "click centered on {}, expected {}",
+            centroid,
+            expected
+        );
+    }
+
+    #[test_case(0.2 ; "five times faster")]
+    #[test_case(0.05 ; "twenty times faster")]
+    #[test_case(0.01 ; "a hundred times faster")]
+    fn extreme_speed_ups_finish_short_inputs(factor: f32) {
+        let window_len = 1024;
+        let (mut stretcher, tx) = TestStretcher {
+            time_map: TimeMap::constant(factor),
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(generate_noise(4410, 1)).unwrap();
+        drop(tx);
+        let windows =
+            std::iter::from_fn(|| (!stretcher.is_done()).then(|| stretcher.next_window())).count();
+        // the stretched input is shorter than a window
+        assert!((1..=2).contains(&windows), "{} windows", windows);
+    }
+
+    #[test]
+    fn speed_ups_skip_input_that_hasnt_arrived_yet() {
+        let input = generate_noise(44100, 2);
+        let render = |chunk_len: usize| {
+            let (mut stretcher, tx) = TestStretcher {
+                time_map: TimeMap::constant(0.05),
+                ..TestStretcher::new(windows::hanning(1024))
+            }
+            .build();
+            for chunk in input.chunks(chunk_len) {
+                tx.send(chunk.to_vec()).unwrap();
+            }
+            drop(tx);
+            let mut output = vec![];
+            while !stretcher.is_done() {
+                output.extend(stretcher.next_window());
+            }
+            output
+        };
+        // each step skips over many chunks at a time
+        assert_eq!(render(1000), render(input.len()));
+    }
+
+    #[test_case(true, 900.0, 2250.0 ; "formants stay put when preserved")]
+    #[test_case(false, 1350.0, 3375.0 ; "formants move with pitch otherwise")]
+    fn formant_preservation_keeps_formant_peaks(
+        preserve_formants: bool,
+        expected_first_formant: f32,
+        expected_second_formant: f32,
+    ) {
+        let window_len = 2048;
+        let input = formant_filtered_pulse_train(150.0, &[900.0, 2250.0], window_len * 16);
+        let (mut stretcher, tx) = TestStretcher {
+            pitch_curve: PitchCurve::constant(1.5),
+            resynth_mode: ResynthMode::PhaseVocoder(PhaseLocking::Identity),
+            preserve_formants,
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(input).unwrap();
+        let output: Vec<f32> = (0..12).flat_map(|_| stretcher.next_window()).collect();
+        let spectrum = magnitude_spectrum(&output[window_len * 2..window_len * 10]);
+        let first_formant = loudest_frequency(&spectrum, 44100, 500.0, 1700.0);
+        let second_formant = loudest_frequency(&spectrum, 44100, 1800.0, 3800.0);
+        // formants sit on harmonics both before and after the shift, so the
+        // loudest harmonic near each one marks where it ended up
+        assert!((first_formant - expected_first_formant).abs() < 50.0);
+        assert!((second_formant - expected_second_formant).abs() < 50.0);
+    }
+
+    /// A pulse train at `f0` through a cascade of resonators at `formants`
+    fn formant_filtered_pulse_train(f0: f32, formants: &[f32], len: usize) -> Vec<f32> {
+        let period = 44100.0 / f0;
+        let mut signal: Vec<f32> = (0..len)
+            .map(|i| if (i as f32 % period) < 1.0 { 1.0 } else { 0.0 })
+            .collect();
+        for formant in formants {
+            let r = (-std::f32::consts::PI * 100.0 / 44100.0).exp();
+            let theta = 2.0 * std::f32::consts::PI * formant / 44100.0;
+            let (mut y1, mut y2) = (0.0, 0.0);
+            for sample in signal.iter_mut() {
+                let y = *sample * (1.0 - r) + 2.0 * r * theta.cos() * y1 - r * r * y2;
+                y2 = y1;
+                y1 = y;
+                *sample = y;
+            }
+        }
+        signal
+    }
+
+    #[test]
+    fn time_map_varies_stretch_over_input() {
+        let window_len = 1024;
+        let time_map: TimeMap = "0, 1; 1, 4".parse().unwrap();
+        let input_len = 44100 * 2;
+        let (mut stretcher, tx) = TestStretcher {
+            time_map: time_map.clone(),
+            ..TestStretcher::new(windows::hanning(window_len))
+        }
+        .build();
+        tx.send(vec![0.0; input_len]).unwrap();
+        drop(tx);
+        let mut output_len = 0;
+        while !stretcher.is_done() {
+            output_len += stretcher.next_window().len();
+        }
+        let expected_len = time_map.stretched_len(input_len, 44100);
+        assert!(output_len.abs_diff(expected_len) <= window_len * 2);
+    }
+
+    fn basic_stretcher(window_len: usize) -> (Stretcher, Sender<Vec<f32>>) {
+        TestStretcher::new(vec![1.0; window_len]).build()
+    }
+
+    /// Stretcher settings for tests, defaulting to random-phase resynthesis
+    /// with no stretch or pitch shift
+    struct TestStretcher {
+        window: Vec<f32>,
+        overlap: usize,
+        time_map: TimeMap,
+        pitch_curve: PitchCurve,
+        resynth_mode: ResynthMode,
+        transient_sensitivity: Option<f32>,
+        phase_randomizer: PhaseRandomizer,
+        preserve_formants: bool,
+    }
+
+    impl TestStretcher {
+        fn new(window: Vec<f32>) -> TestStretcher {
+            TestStretcher {
+                window,
+                overlap: 2,
+                time_map: TimeMap::constant(1.0),
+                pitch_curve: PitchCurve::constant(1.0),
+                resynth_mode: ResynthMode::RandomPhase,
+                transient_sensitivity: None,
+                phase_randomizer: PhaseRandomizer::for_channels(1, ChannelPhases::default(), 0)
+                    .pop()
+                    .unwrap(),
+                preserve_formants: false,
+            }
+        }
+
+        fn build(self) -> (Stretcher, Sender<Vec<f32>>) {
+            let (tx, rx) = unbounded();
+            let stretcher = Stretcher::new(
+                AudioSpec {
+                    channels: 2,
+                    sample_rate: 44100,
+                },
+                rx,
+                self.time_map,
+                1.0,
+                self.pitch_curve,
+                ResampleQuality::default(),
+                self.window,
+                self.overlap,
+                self.resynth_mode,
+                self.transient_sensitivity,
+                self.phase_randomizer,
+                self.preserve_formants,
+                Duration::from_secs(1),
+                0,
+                KernelClock::AudioTime,
+                vec![],
+            );
+            (stretcher, tx)
+        }
     }
 }
@@ -1,15 +1,24 @@
-use crate::audio::AudioBus;
+use crate::audio::{self, AudioBus, ExpectedLength};
 use crate::signal_flow::node::{ControlMessage, Processor, ProcessorState};
 use crate::stretcher::Stretcher;
 use anyhow::Result;
 use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
 #[derive(Debug)]
 pub enum StretcherProcessorControlMessage {
     Shutdown,
+    /// Freeze every channel's current analysis window, sustaining it until released
+    Freeze,
+    /// Release a freeze and carry on stretching the input
+    Release,
+    /// Bypass every channel's frequency kernel at `stage` in its chain, or bring it back
+    BypassKernel {
+        stage: usize,
+        bypassed: bool,
+    },
 }
 
 impl ControlMessage for StretcherProcessorControlMessage {
@@ -19,31 +28,69 @@ impl ControlMessage for StretcherProcessorControlMessage {
 }
 
 pub struct StretcherProcessor {
-    channels: Vec<(Sender<Vec<f32>>, Stretcher)>,
+    channels: Vec<Arc<Mutex<Channel>>>,
+    decode_mid_side: bool,
+    /// the length expected before any live freezes
+    unfrozen_total_samples: Option<usize>,
+    expected_total_samples: ExpectedLength,
 }
 
 impl StretcherProcessor {
+    /// With `decode_mid_side`, the two stretchers are taken to be processing
+    /// mid and side channels, and their output is decoded back to left and right.
     pub fn new(
         channel_stretchers: Vec<Stretcher>,
         expected_total_samples: Option<usize>,
+        decode_mid_side: bool,
     ) -> (StretcherProcessor, AudioBus) {
+        assert!(!decode_mid_side || channel_stretchers.len() == 2);
         let spec = channel_stretchers[0].spec;
-        let mut channels: Vec<(Sender<Vec<f32>>, Stretcher)> = vec![];
+        let mut channels: Vec<Arc<Mutex<Channel>>> = vec![];
         let mut receivers: Vec<Receiver<Vec<f32>>> = vec![];
         for stretcher in channel_stretchers.into_iter() {
             let (tx, rx) = bounded(stretcher.channel_bound());
-            channels.push((tx, stretcher));
+            channels.push(Arc::new(Mutex::new(Channel {
+                output: tx,
+                window: vec![0.0; stretcher.window_len()],
+                window_filled: 0,
+                stretcher,
+            })));
             receivers.push(rx);
         }
+        let shared_total_samples = ExpectedLength::new(expected_total_samples);
         (
-            StretcherProcessor { channels },
+            StretcherProcessor {
+                channels,
+                decode_mid_side,
+                unfrozen_total_samples: expected_total_samples,
+                expected_total_samples: shared_total_samples.clone(),
+            },
             AudioBus {
                 spec,
                 channels: receivers,
-                expected_total_samples,
+                expected_total_samples: shared_total_samples,
             },
         )
     }
+
+    /// Generate the next window of every channel, with `workers` stretching
+    /// all but the first
+    fn next_windows(&self, workers: &[Worker]) {
+        for worker in workers {
+            worker.jobs.send(()).unwrap();
+        }
+        // the first channel is stretched here rather than idly waiting on the others
+        self.channels[0].lock().unwrap().next_window();
+        for worker in workers {
+            worker.done.recv().unwrap();
+        }
+    }
+
+    fn for_each_stretcher(&self, f: impl Fn(&mut Stretcher)) {
+        for channel in self.channels.iter() {
+            f(&mut channel.lock().unwrap().stretcher);
+        }
+    }
 }
 
 impl Processor<StretcherProcessorControlMessage> for StretcherProcessor {
@@ -53,20 +100,36 @@ impl Processor<StretcherProcessorControlMessage> for StretcherProcessor {
     ) -> (Sender<StretcherProcessorControlMessage>, JoinHandle<()>) {
         let (ctrl_tx, ctrl_rx) = unbounded();
         let handle = thread::spawn(move || {
+            // each other channel is stretched on its own thread for as long as this runs
+            let workers: Vec<Worker> = self.channels[1..]
+                .iter()
+                .map(|channel| Worker::spawn(channel.clone()))
+                .collect();
             'outer: loop {
-                match self.handle_control_messages(&ctrl_rx).unwrap() {
-                    ProcessorState::Finished => {
-                        break 'outer;
-                    }
-                    _ => {}
+                if let ProcessorState::Finished = self.handle_control_messages(&ctrl_rx).unwrap() {
+                    break 'outer;
+                }
+                // assuming each stretcher finishes at the same time
+                if self
+                    .channels
+                    .iter()
+                    .any(|channel| channel.lock().unwrap().stretcher.is_done())
+                {
+                    info!("stretch process completed");
+                    break 'outer;
                 }
-                for (output, stretcher) in self.channels.iter_mut() {
-                    if stretcher.is_done() {
-                        // assuming each stretcher finishes at the same time
-                        info!("stretch process completed");
-                        break 'outer;
-                    }
-                    output.send(stretcher.next_window()).unwrap();
+                self.next_windows(&workers);
+                if self.decode_mid_side {
+                    let mut mid = self.channels[0].lock().unwrap();
+                    let mut side = self.channels[1].lock().unwrap();
+                    audio::decode_mid_side(&mut mid.window, &mut side.window);
+                }
+                for channel in self.channels.iter() {
+                    let channel = channel.lock().unwrap();
+                    channel
+                        .output
+                        .send(channel.window[..channel.window_filled].to_vec())
+                        .unwrap();
                 }
             }
             finished.store(true, Ordering::Relaxed);
@@ -81,9 +144,135 @@ impl Processor<StretcherProcessorControlMessage> for StretcherProcessor {
         match rx.try_recv() {
             Ok(msg) => match msg {
                 StretcherProcessorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                StretcherProcessorControlMessage::Freeze => {
+                    self.for_each_stretcher(|stretcher| stretcher.freeze());
+                    // there's no knowing when the end comes until it's released
+                    self.expected_total_samples.set(None);
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::Release => {
+                    self.for_each_stretcher(|stretcher| stretcher.release());
+                    let frozen_len = self.channels[0].lock().unwrap().stretcher.live_frozen_len();
+                    self.expected_total_samples.set(
+                        self.unfrozen_total_samples
+                            .map(|total_samples| total_samples + frozen_len),
+                    );
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::BypassKernel { stage, bypassed } => {
+                    self.for_each_stretcher(|stretcher| stretcher.bypass_kernel(stage, bypassed));
+                    Ok(ProcessorState::Running)
+                }
             },
             Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
             Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
         }
     }
 }
+
+/// A channel's stretcher, with the window buffer it stretches into
+struct Channel {
+    output: Sender<Vec<f32>>,
+    stretcher: Stretcher,
+    window: Vec<f32>,
+    /// how much of `window` is output
+    window_filled: usize,
+}
+
+impl Channel {
+    fn next_window(&mut self) {
+        self.window_filled = self.stretcher.next_window_into(&mut self.window);
+    }
+}
+
+/// A thread that stretches the next window of one channel whenever it's
+/// asked to, until it's dropped
+struct Worker {
+    jobs: Sender<()>,
+    done: Receiver<()>,
+}
+
+impl Worker {
+    fn spawn(channel: Arc<Mutex<Channel>>) -> Self {
+        let (jobs, jobs_rx) = bounded(1);
+        let (done_tx, done) = bounded(1);
+        thread::spawn(move || {
+            for () in jobs_rx {
+                channel.lock().unwrap().next_window();
+                if done_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Worker { jobs, done }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+    use crate::fft::{ChannelPhases, KernelClock, PhaseRandomizer, ResynthMode};
+    use crate::pitch_curve::PitchCurve;
+    use crate::resampler::ResampleQuality;
+    use crate::test_utils::*;
+    use crate::time_map::TimeMap;
+    use crate::windows;
+    use std::time::Duration;
+
+    #[test]
+    fn parallel_channels_match_serial_stretching() {
+        let channels = 3;
+        let mut expected: Vec<Vec<f32>> = vec![];
+        for mut stretcher in seeded_stretchers(channels) {
+            let mut output = vec![];
+            while !stretcher.is_done() {
+                output.extend(stretcher.next_window());
+            }
+            expected.push(output);
+        }
+
+        let (processor, bus) = StretcherProcessor::new(seeded_stretchers(channels), None, false);
+        let (_ctrl_tx, handle) = processor.start(Arc::new(AtomicBool::new(false)));
+        handle.join().unwrap();
+        for (rx, expected) in bus.channels.iter().zip(expected) {
+            let output: Vec<f32> = rx.try_iter().flatten().collect();
+            assert_eq!(output, expected);
+        }
+    }
+
+    /// Stretchers with unrelated random phases, each fed its own noise
+    fn seeded_stretchers(channels: usize) -> Vec<Stretcher> {
+        let spec = AudioSpec {
+            channels: channels as u16,
+            sample_rate: 44100,
+        };
+        PhaseRandomizer::for_channels(channels, ChannelPhases::Wide, 0)
+            .into_iter()
+            .enumerate()
+            .map(|(channel, phase_randomizer)| {
+                let (tx, rx) = unbounded();
+                tx.send(generate_noise(8192, channel as u64)).unwrap();
+                Stretcher::new(
+                    spec,
+                    rx,
+                    TimeMap::constant(3.0),
+                    1.0,
+                    PitchCurve::constant(1.0),
+                    ResampleQuality::default(),
+                    windows::hanning(1024),
+                    2,
+                    ResynthMode::RandomPhase,
+                    None,
+                    phase_randomizer,
+                    false,
+                    // big enough for the output bus to hold the whole stretch
+                    Duration::from_micros(100),
+                    channel,
+                    KernelClock::AudioTime,
+                    vec![],
+                )
+            })
+            .collect()
+    }
+}
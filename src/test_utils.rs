@@ -1,4 +1,9 @@
 use crate::audio::{Audio, AudioSpec};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustfft::num_complex::Complex32;
+use rustfft::FftPlanner;
+use std::f32::consts::PI;
 use std::fmt::Debug;
 
 const F32_EPSILON: f32 = 1.0e-4;
@@ -80,3 +85,94 @@ pub fn generate_audio(fill_val: f32, len: usize, channels: u16, sample_rate: u32
     }
     audio
 }
+
+#[allow(unused)]
+pub fn generate_sine(freq: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| (2.0 * PI * freq * i as f32 / sample_rate as f32).sin())
+        .collect()
+}
+
+/// A sine whose frequency rises linearly from `start_freq` to `end_freq`
+#[allow(unused)]
+pub fn generate_sweep(start_freq: f32, end_freq: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+    let duration = len as f64 / sample_rate as f64;
+    let rate = (end_freq - start_freq) as f64 / duration;
+    (0..len)
+        .map(|i| {
+            let t = i as f64 / sample_rate as f64;
+            let phase = 2.0 * std::f64::consts::PI * (start_freq as f64 * t + rate * t * t / 2.0);
+            phase.sin() as f32
+        })
+        .collect()
+}
+
+/// Uniform white noise between -1 and 1
+#[allow(unused)]
+pub fn generate_noise(len: usize, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect()
+}
+
+#[allow(unused)]
+pub fn rms(samples: &[f32]) -> f32 {
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Ratio of a signal's peak to its RMS level
+#[allow(unused)]
+pub fn crest_factor(samples: &[f32]) -> f32 {
+    let peak = samples.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+    peak / rms(samples)
+}
+
+/// Magnitudes of the non-negative frequency bins of a Hann windowed signal
+#[allow(unused)]
+pub fn magnitude_spectrum(samples: &[f32]) -> Vec<f32> {
+    let len = samples.len();
+    let mut buf: Vec<Complex32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let w = 0.5 - 0.5 * (2.0 * PI * i as f32 / len as f32).cos();
+            Complex32::new(s * w, 0.0)
+        })
+        .collect();
+    FftPlanner::new().plan_fft_forward(len).process(&mut buf);
+    buf[..len / 2 + 1].iter().map(|c| c.norm()).collect()
+}
+
+/// The frequency of the loudest bin of `spectrum` between `min_freq` and `max_freq`
+#[allow(unused)]
+pub fn loudest_frequency(spectrum: &[f32], sample_rate: u32, min_freq: f32, max_freq: f32) -> f32 {
+    let bin_width = sample_rate as f32 / ((spectrum.len() - 1) * 2) as f32;
+    let bins = (min_freq / bin_width) as usize..(max_freq / bin_width) as usize;
+    let loudest = bins
+        .max_by(|a, b| spectrum[*a].total_cmp(&spectrum[*b]))
+        .unwrap();
+    loudest as f32 * bin_width
+}
+
+/// Pearson correlation coefficient of two equally long signals
+#[allow(unused)]
+pub fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean = |s: &[f32]| s.iter().sum::<f32>() / s.len() as f32;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
+#[allow(unused)]
+pub fn zero_crossings(samples: &[f32]) -> usize {
+    samples
+        .windows(2)
+        .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+        .count()
+}
@@ -0,0 +1,112 @@
+use crate::breakpoints::Breakpoints;
+use anyhow::{bail, Error, Result};
+use std::str::FromStr;
+
+/// A stretch factor that varies over the course of the input
+///
+/// Defined by breakpoints of input time and stretch factor, given either
+/// in a file or inline like `0, 2; 1:00, 50`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeMap {
+    factors: Breakpoints,
+}
+
+impl TimeMap {
+    pub fn constant(factor: f32) -> TimeMap {
+        TimeMap {
+            factors: Breakpoints::constant(factor),
+        }
+    }
+
+    /// A constant stretch which makes `input_len` samples last `output_len`
+    pub fn fitting(input_len: usize, output_len: usize) -> TimeMap {
+        TimeMap::constant((output_len as f64 / input_len as f64) as f32)
+    }
+
+    /// The stretch factor at `time` seconds into the input
+    pub fn factor_at(&self, time: f32) -> f32 {
+        self.factors.value_at(time)
+    }
+
+    /// How long the first `duration` seconds of input last once stretched
+    pub fn stretched_duration(&self, duration: f64) -> f64 {
+        self.factors.integral(duration)
+    }
+
+    /// How many samples `input_len` samples of input last once stretched
+    pub fn stretched_len(&self, input_len: usize, sample_rate: u32) -> usize {
+        let stretched_len = match self.factors.constant_value() {
+            Some(factor) => input_len as f64 * factor as f64,
+            None => {
+                self.stretched_duration(input_len as f64 / sample_rate as f64) * sample_rate as f64
+            }
+        };
+        stretched_len.round() as usize
+    }
+}
+
+impl FromStr for TimeMap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let factors = Breakpoints::from_arg(s, |value| {
+            let factor: f32 = value.parse()?;
+            if !factor.is_finite() || factor <= 0.0 {
+                bail!("Stretch factor must be a positive number");
+            }
+            Ok(factor)
+        })?;
+        Ok(TimeMap { factors })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use test_case::test_case;
+
+    #[test_case("0, 0" ; "zero factor")]
+    #[test_case("0, -2" ; "negative factor")]
+    #[test_case("0, 2; 1, fast" ; "nonsense factor")]
+    fn from_str_rejects_invalid_factor(src: &str) {
+        assert!(src.parse::<TimeMap>().is_err());
+    }
+
+    #[test]
+    fn stretched_duration_integrates_factor() {
+        let map: TimeMap = "0, 1; 10, 3".parse().unwrap();
+        assert_almost_eq(map.factor_at(5.0), 2.0);
+        assert_almost_eq(map.stretched_duration(12.0) as f32, 26.0);
+    }
+
+    #[test_case(3.0, 132300 ; "slowed down")]
+    #[test_case(0.01, 441 ; "sped up a hundredfold")]
+    fn constant_map_scales_length(factor: f32, expected: usize) {
+        assert_eq!(
+            TimeMap::constant(factor).stretched_len(44100, 44100),
+            expected
+        );
+    }
+
+    #[test_case(TimeMap::constant(1.7) ; "constant")]
+    #[test_case("0, 1.7; 1, 1.7".parse().unwrap() ; "breakpoints")]
+    fn stretched_len_is_exact_for_long_input(map: TimeMap) {
+        // 2 hours at 44.1kHz, where single precision is out by several samples
+        assert_eq!(
+            map.stretched_len(317_520_001, 44100),
+            (317_520_001.0 * 1.7f32 as f64).round() as usize
+        );
+    }
+
+    #[test_case(44100, 132300 ; "slowed down")]
+    #[test_case(132300, 44100 ; "sped up")]
+    #[test_case(10007, 48000 ; "uneven lengths")]
+    #[test_case(1323000, 5 ; "to almost nothing")]
+    fn fitting_map_stretches_to_output_len(input_len: usize, output_len: usize) {
+        assert_eq!(
+            TimeMap::fitting(input_len, output_len).stretched_len(input_len, 44100),
+            output_len
+        );
+    }
+}
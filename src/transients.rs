@@ -0,0 +1,104 @@
+use rustfft::num_complex::Complex32;
+use std::collections::VecDeque;
+
+/// How many past windows the adaptive flux threshold averages over
+const FLUX_HISTORY_LEN: usize = 8;
+/// Normalized flux below which nothing is considered a transient, however quiet the history
+const MIN_FLUX: f32 = 0.05;
+
+/// Onset detection from the spectral flux between consecutive analysis windows
+///
+/// Flux is the summed increase in each bin's magnitude, normalized by the
+/// window's total magnitude so detection doesn't depend on level. A window
+/// is a transient when its flux is rising and exceeds the recent average
+/// by a margin set by `sensitivity`.
+pub struct TransientDetector {
+    sensitivity: f32,
+    last_magnitudes: Vec<f32>,
+    last_flux: f32,
+    flux_history: VecDeque<f32>,
+    primed: bool,
+}
+
+impl TransientDetector {
+    /// How many previous windows detection depends on
+    pub const MEMORY_WINDOWS: usize = FLUX_HISTORY_LEN + 1;
+
+    /// `sensitivity` ranges from 0 (never detects) to 1 (detects any rise in flux)
+    pub fn new(window_len: usize, sensitivity: f32) -> TransientDetector {
+        TransientDetector {
+            sensitivity: sensitivity.clamp(0.0, 1.0),
+            last_magnitudes: vec![0.0; window_len / 2 + 1],
+            last_flux: 0.0,
+            flux_history: VecDeque::with_capacity(FLUX_HISTORY_LEN),
+            primed: false,
+        }
+    }
+
+    /// Analyze the next window's spectrum, returning whether it starts a transient
+    pub fn detect(&mut self, spectrum: &[Complex32]) -> bool {
+        let mut rise = 0.0;
+        let mut total = 0.0;
+        for (bin, last_magnitude) in spectrum.iter().zip(self.last_magnitudes.iter_mut()) {
+            let magnitude = bin.norm();
+            rise += (magnitude - *last_magnitude).max(0.0);
+            total += magnitude;
+            *last_magnitude = magnitude;
+        }
+        let flux = if self.primed && total > 0.0 {
+            rise / total
+        } else {
+            0.0
+        };
+        let average_flux = if self.flux_history.is_empty() {
+            0.0
+        } else {
+            self.flux_history.iter().sum::<f32>() / self.flux_history.len() as f32
+        };
+        let is_transient = self.primed
+            && self.sensitivity > 0.0
+            && flux > MIN_FLUX
+            && flux > self.last_flux
+            && flux > average_flux / self.sensitivity;
+        if self.flux_history.len() == FLUX_HISTORY_LEN {
+            self.flux_history.pop_front();
+        }
+        self.flux_history.push_back(flux);
+        self.last_flux = flux;
+        self.primed = true;
+        is_transient
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn steady_spectrum_is_not_a_transient() {
+        let mut detector = TransientDetector::new(6, 1.0);
+        let spectrum = vec![Complex32::new(1.0, 0.0); 4];
+        for _ in 0..4 {
+            assert!(!detector.detect(&spectrum));
+        }
+    }
+
+    #[test]
+    fn sudden_broadband_rise_is_a_transient() {
+        let mut detector = TransientDetector::new(6, 0.5);
+        let quiet = vec![Complex32::new(0.1, 0.0); 4];
+        let loud = vec![Complex32::new(0.0, 1.0); 4];
+        for _ in 0..4 {
+            detector.detect(&quiet);
+        }
+        assert!(detector.detect(&loud));
+        assert!(!detector.detect(&loud));
+    }
+
+    #[test]
+    fn zero_sensitivity_never_detects() {
+        let mut detector = TransientDetector::new(6, 0.0);
+        detector.detect(&[Complex32::new(0.0, 0.0); 4]);
+        assert!(!detector.detect(&[Complex32::new(1.0, 0.0); 4]));
+    }
+}
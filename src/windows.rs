@@ -1,4 +1,75 @@
+use anyhow::{bail, Context, Error, Result};
 use std::f32;
+use std::str::FromStr;
+
+/// A window function shaping each analysis and resynthesis window
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum WindowFn {
+    #[default]
+    Hann,
+    Blackman,
+    BlackmanHarris,
+    /// Kaiser window with the given beta
+    Kaiser(f32),
+    /// Gaussian window with the given standard deviation, as a fraction of half the window
+    Gaussian(f32),
+    /// Tukey window with the given fraction of the window tapered
+    Tukey(f32),
+}
+
+impl WindowFn {
+    pub fn generate(&self, len: usize) -> Vec<f32> {
+        match *self {
+            WindowFn::Hann => hanning(len),
+            WindowFn::Blackman => blackman(len),
+            WindowFn::BlackmanHarris => blackman_harris(len),
+            WindowFn::Kaiser(beta) => kaiser(len, beta),
+            WindowFn::Gaussian(sigma) => gaussian(len, sigma),
+            WindowFn::Tukey(alpha) => tukey(len, alpha),
+        }
+    }
+}
+
+impl FromStr for WindowFn {
+    type Err = Error;
+
+    /// Parse a window name, optionally followed by its parameter after a
+    /// colon, e.g. `kaiser:8`
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => {
+                let param: f32 = param
+                    .parse()
+                    .with_context(|| format!("Invalid window parameter '{}'", param))?;
+                (name, Some(param))
+            }
+            None => (s, None),
+        };
+        let window_fn = match (name, param) {
+            ("hann" | "hanning", None) => WindowFn::Hann,
+            ("blackman", None) => WindowFn::Blackman,
+            ("blackman-harris", None) => WindowFn::BlackmanHarris,
+            ("kaiser", beta) => WindowFn::Kaiser(beta.unwrap_or(8.0)),
+            ("gaussian", sigma) => WindowFn::Gaussian(sigma.unwrap_or(0.4)),
+            ("tukey", alpha) => WindowFn::Tukey(alpha.unwrap_or(0.5)),
+            ("hann" | "hanning" | "blackman" | "blackman-harris", Some(_)) => {
+                bail!("Window '{}' takes no parameter", name)
+            }
+            _ => bail!(
+                "Invalid window function '{}'; expected 'hann', 'blackman', 'blackman-harris', 'kaiser', 'gaussian' or 'tukey'",
+                s
+            ),
+        };
+        match window_fn {
+            WindowFn::Kaiser(beta) if beta < 0.0 => bail!("Kaiser beta must not be negative"),
+            WindowFn::Gaussian(sigma) if sigma <= 0.0 => bail!("Gaussian sigma must be positive"),
+            WindowFn::Tukey(alpha) if !(0.0..=1.0).contains(&alpha) => {
+                bail!("Tukey alpha must be between 0 and 1")
+            }
+            _ => Ok(window_fn),
+        }
+    }
+}
 
 /// The 'upper part' of a cosine period
 pub fn hanning(len: usize) -> Vec<f32> {
@@ -8,6 +79,48 @@ pub fn hanning(len: usize) -> Vec<f32> {
         .collect()
 }
 
+/// Three-term cosine window with sidelobes about 58dB down
+pub fn blackman(len: usize) -> Vec<f32> {
+    cosine_sum(len, &[0.42, 0.5, 0.08])
+}
+
+/// Four-term cosine window with sidelobes about 92dB down
+pub fn blackman_harris(len: usize) -> Vec<f32> {
+    cosine_sum(len, &[0.35875, 0.48829, 0.14128, 0.01168])
+}
+
+/// Kaiser window; higher `beta` trades a wider main lobe for lower sidelobes
+pub fn kaiser(len: usize, beta: f32) -> Vec<f32> {
+    let denominator = bessel_i0(beta as f64);
+    (0..len)
+        .map(|i| {
+            let x = centered_position(i, len) as f64;
+            (bessel_i0(beta as f64 * (1.0 - x * x).max(0.0).sqrt()) / denominator) as f32
+        })
+        .collect()
+}
+
+/// Gaussian window with standard deviation `sigma` relative to half the window
+pub fn gaussian(len: usize, sigma: f32) -> Vec<f32> {
+    (0..len)
+        .map(|i| (-0.5 * (centered_position(i, len) / sigma).powi(2)).exp())
+        .collect()
+}
+
+/// Flat-topped window with the outer `alpha` of its length tapered by a cosine
+pub fn tukey(len: usize, alpha: f32) -> Vec<f32> {
+    (0..len)
+        .map(|i| {
+            let distance_from_edge = 1.0 - centered_position(i, len).abs();
+            if distance_from_edge >= alpha {
+                1.0
+            } else {
+                0.5 - 0.5 * (f32::consts::PI * distance_from_edge / alpha).cos()
+            }
+        })
+        .collect()
+}
+
 /// A naive always-1.0 window
 pub fn rectangular(len: usize) -> Vec<f32> {
     vec![1.0; len]
@@ -20,10 +133,44 @@ pub fn inverse(elements: &[f32]) -> Vec<f32> {
     elements.iter().map(|f| 1.0 / f).collect()
 }
 
+/// Sum of alternating cosine harmonics with the given coefficients
+fn cosine_sum(len: usize, coefficients: &[f32]) -> Vec<f32> {
+    let two_pi = f32::consts::PI * 2.0;
+    (0..len)
+        .map(|i| {
+            let phase = (i as f32 * two_pi) / (len - 1) as f32;
+            coefficients
+                .iter()
+                .enumerate()
+                .map(|(k, a)| if k % 2 == 0 { 1.0 } else { -1.0 } * a * (phase * k as f32).cos())
+                .sum()
+        })
+        .collect()
+}
+
+/// Position of sample `i` from -1 at the window's start to 1 at its end
+fn centered_position(i: usize, len: usize) -> f32 {
+    2.0 * i as f32 / (len - 1) as f32 - 1.0
+}
+
+/// Zeroth order modified Bessel function of the first kind
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::test_utils::*;
+    use test_case::test_case;
 
     #[test]
     #[rustfmt::skip]
@@ -42,6 +189,65 @@ mod test {
 	assert_almost_eq_by_element(result, expected);
     }
 
+    #[test]
+    fn blackman_result() {
+        let result = blackman(5);
+        assert_almost_eq_by_element(result, vec![0.0, 0.34, 1.0, 0.34, 0.0]);
+    }
+
+    #[test]
+    fn blackman_harris_result() {
+        let result = blackman_harris(5);
+        assert_almost_eq_by_element(result, vec![0.00006, 0.21747, 1.0, 0.21747, 0.00006]);
+    }
+
+    #[test]
+    fn kaiser_without_beta_is_rectangular() {
+        assert_almost_eq_by_element(kaiser(4, 0.0), rectangular(4));
+    }
+
+    #[test]
+    fn kaiser_result() {
+        let result = kaiser(5, 8.0);
+        // I0(8 * sqrt(0.75)) / I0(8)
+        assert_almost_eq_by_element(result, vec![0.00234, 0.36897, 1.0, 0.36897, 0.00234]);
+    }
+
+    #[test]
+    fn gaussian_result() {
+        let result = gaussian(5, 0.5);
+        assert_almost_eq_by_element(result, vec![0.13534, 0.60653, 1.0, 0.60653, 0.13534]);
+    }
+
+    #[test]
+    fn tukey_extremes() {
+        assert_almost_eq_by_element(tukey(32, 0.0), rectangular(32));
+        assert_almost_eq_by_element(tukey(32, 1.0), hanning(32));
+    }
+
+    #[test]
+    fn tukey_has_flat_top() {
+        let result = tukey(9, 0.5);
+        assert_almost_eq_by_element(result, vec![0.0, 0.5, 1.0, 1.0, 1.0, 1.0, 1.0, 0.5, 0.0]);
+    }
+
+    #[test_case("hann", WindowFn::Hann ; "hann")]
+    #[test_case("blackman-harris", WindowFn::BlackmanHarris ; "blackman harris")]
+    #[test_case("kaiser", WindowFn::Kaiser(8.0) ; "default kaiser beta")]
+    #[test_case("kaiser:12.5", WindowFn::Kaiser(12.5) ; "kaiser beta")]
+    #[test_case("tukey:0.25", WindowFn::Tukey(0.25) ; "tukey alpha")]
+    fn window_fn_from_str(s: &str, expected: WindowFn) {
+        assert_eq!(s.parse::<WindowFn>().unwrap(), expected);
+    }
+
+    #[test_case("triangle" ; "unknown window")]
+    #[test_case("blackman:3" ; "unexpected parameter")]
+    #[test_case("kaiser:big" ; "nonsense parameter")]
+    #[test_case("tukey:2" ; "out of range parameter")]
+    fn window_fn_from_str_rejects_invalid(s: &str) {
+        assert!(s.parse::<WindowFn>().is_err());
+    }
+
     #[test]
     fn rectangular_result() {
         let result = rectangular(4);